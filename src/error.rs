@@ -0,0 +1,107 @@
+use std::error::Error;
+use std::fmt;
+
+/// Everything that can go wrong in BindRS. Only `main` decides which of these end the process,
+/// using `exit_code` so scripts can tell them apart.
+#[derive(Debug)]
+pub enum BindrsError {
+    /// Bad command line arguments or local setup.
+    Config(String),
+    /// The remote couldn't be checked, prepared or started.
+    Remote(String),
+    /// The initial rsync failed.
+    Rsync(String),
+    /// Watching the local directory failed.
+    Watch(String),
+    /// The remote sent something that doesn't fit the protocol.
+    Protocol(String),
+    /// The connection to the remote closed or went silent.
+    Disconnected(String),
+    /// A single file couldn't be read or applied. The session carries on, and the remote is told
+    /// through its acknowledgement.
+    File(String),
+    /// The remote named a path outside the synced directory. Refused like a failed `File`.
+    Path(String),
+    /// A broken invariant inside BindRS, such as a poisoned lock.
+    Internal(String),
+}
+
+impl BindrsError {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            BindrsError::Internal(_) => 1,
+            BindrsError::Config(_) => 2,
+            BindrsError::Remote(_) => 3,
+            BindrsError::Rsync(_) => 4,
+            BindrsError::Watch(_) => 5,
+            BindrsError::Protocol(_) => 6,
+            BindrsError::Disconnected(_) => 7,
+            BindrsError::File(_) => 8,
+            BindrsError::Path(_) => 9,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match *self {
+            BindrsError::Config(ref msg) |
+            BindrsError::Remote(ref msg) |
+            BindrsError::Rsync(ref msg) |
+            BindrsError::Watch(ref msg) |
+            BindrsError::Protocol(ref msg) |
+            BindrsError::Disconnected(ref msg) |
+            BindrsError::File(ref msg) |
+            BindrsError::Path(ref msg) |
+            BindrsError::Internal(ref msg) => msg,
+        }
+    }
+}
+
+impl fmt::Display for BindrsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl Error for BindrsError {
+    fn description(&self) -> &str {
+        self.message()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_error() -> Vec<BindrsError> {
+        vec![
+            BindrsError::Config(String::new()),
+            BindrsError::Remote(String::new()),
+            BindrsError::Rsync(String::new()),
+            BindrsError::Watch(String::new()),
+            BindrsError::Protocol(String::new()),
+            BindrsError::Disconnected(String::new()),
+            BindrsError::File(String::new()),
+            BindrsError::Path(String::new()),
+            BindrsError::Internal(String::new()),
+        ]
+    }
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let errors = every_error();
+        let mut codes: Vec<i32> = errors.iter().map(|e| e.exit_code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(errors.len(), codes.len());
+        assert!(!codes.contains(&0));
+    }
+
+    #[test]
+    fn every_exit_code_is_documented() {
+        let readme = include_str!("../README.md");
+        for error in every_error() {
+            let row = format!("\n| {} | ", error.exit_code());
+            assert!(readme.contains(&row), "{:?} has no row in the README's exit code table", error);
+        }
+    }
+}
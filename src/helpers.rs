@@ -1,14 +1,11 @@
+use error::BindrsError;
 use regex::RegexSet;
-use semver::Version;
-#[cfg(test)]
-use slog::Discard;
 use slog::Logger;
-use std::{thread, time};
 #[cfg(test)]
 use std::env::current_dir;
 use std::fs::canonicalize;
 use std::path::Path;
-use std::process::exit;
+use std::sync::{Mutex, MutexGuard};
 use structs::remote_info::RemoteInfo;
 
 pub fn resolve_path(dir: &str) -> Option<String> {
@@ -18,83 +15,38 @@ pub fn resolve_path(dir: &str) -> Option<String> {
     }
 }
 
-#[cfg_attr(feature = "clippy", allow(print_stdout))]
-pub fn print_error_and_exit(msg: &str) {
-    println!("{}", msg);
-    exit(1);
-}
-
-pub fn log_error_and_exit(log: &Logger, msg: &str) {
-    error!(log, "{}", msg);
-    thread::sleep(time::Duration::from_millis(500));
-    exit(1);
+/// Locks `mutex`, naming it in the error if another thread panicked while holding it.
+pub fn lock<'a, T>(mutex: &'a Mutex<T>, name: &str) -> Result<MutexGuard<'a, T>, BindrsError> {
+    mutex.lock().map_err(|_| {
+        BindrsError::Internal(format!("Failed to aquire {} lock, lock poisoned", name))
+    })
 }
 
-pub fn process_ignores(log: &Logger, vec: &mut Vec<String>) -> RegexSet {
+pub fn process_ignores(vec: &mut Vec<String>) -> Result<RegexSet, BindrsError> {
     if vec.is_empty() {
         vec.push("^\\.git(?:/[^/]+)*$".to_owned());
     }
     vec.push("^\\.bindrs.*$".to_owned());
+    // Files are written to a temp file beside them and renamed into place
+    vec.push("^(?:.*/)?\\.[^/]*\\.bindrs-tmp$".to_owned());
 
-    vec_to_regex_set(log, vec)
-}
-
-pub fn compare_version_strings(log: &Logger, local_version_str: &str, remote_version_str: &str) {
-    let local_version = Version::parse(local_version_str).unwrap_or_else(|e| {
-        log_error_and_exit(
-            log,
-            &format!("Could not parse local version: {}", local_version_str),
-        );
-        panic!(e)
-    });
-    let remote_version = Version::parse(remote_version_str).unwrap_or_else(|e| {
-        log_error_and_exit(
-            log,
-            &format!("Could not parse remote version: {}", remote_version_str),
-        );
-        panic!(e)
-    });
-
-    if local_version == remote_version {
-        return;
-    }
-
-    if versions_are_compatible(&local_version, &remote_version) {
-        warn!(
-            log,
-            "BindRS versions differ, consider updating older version to match the newer version. \
-             Local: {} - Remote: {}",
-            local_version_str,
-            remote_version_str
-        );
-    } else {
-        log_error_and_exit(
-            log,
-            &format!(
-                "BindRS versions too different between local and remote. \
-                 Please update older version to match newer version. Local: {} - Remote: {}",
-                local_version_str,
-                remote_version_str
-            ),
-        );
-    }
+    vec_to_regex_set(vec)
 }
 
 pub fn download_bindrs(log: &Logger, remote_info: &RemoteInfo) -> bool {
     let host_triple = match remote_info.check_cmd_output(
-        log,
         "uname",
         &["Darwin".to_string(), "Linux".to_string()],
         true,
     ) {
-        Ok(uname) => {
+        Ok(Some(uname)) => {
             match uname.as_ref() {
                 "Darwin" => "x86_64-apple-darwin",
                 "Linux" => "x86_64-unknown-linux-gnu",
                 _ => return false,
             }
         }
-        Err(_) => return false,
+        Ok(None) | Err(_) => return false,
     };
 
     let url = format!(
@@ -116,14 +68,9 @@ pub fn download_bindrs(log: &Logger, remote_info: &RemoteInfo) -> bool {
     }
 }
 
-fn versions_are_compatible(version_a: &Version, version_b: &Version) -> bool {
-    version_a.major == version_b.major && version_a.minor == version_b.minor
-}
-
-fn vec_to_regex_set(log: &Logger, ignores: &[String]) -> RegexSet {
-    RegexSet::new(&convert_to_project_regex_strings(ignores)[..]).unwrap_or_else(|e| {
-        log_error_and_exit(log, &format!("Provided regex failed to parse: {}", e));
-        panic!() // For compilation
+fn vec_to_regex_set(ignores: &[String]) -> Result<RegexSet, BindrsError> {
+    RegexSet::new(&convert_to_project_regex_strings(ignores)[..]).map_err(|e| {
+        BindrsError::Config(format!("Provided regex failed to parse: {}", e))
     })
 }
 
@@ -146,15 +93,11 @@ fn convert_to_project_regex_strings(ignores: &[String]) -> Vec<String> {
 mod tests {
     use super::*;
 
-    fn test_logger() -> Logger {
-        Logger::root(Discard, o!())
-    }
-
     #[test]
     fn including_custom_ignore_skips_git() {
         let mut strings: Vec<String> = vec![];
         strings.push("^something$".to_owned());
-        let regex_set = process_ignores(&test_logger(), &mut strings);
+        let regex_set = process_ignores(&mut strings).unwrap();
         assert!(regex_set.is_match("something"));
         assert!(regex_set.is_match(".bindrsasdf"));
         assert!(!regex_set.is_match(".git/something"));
@@ -163,7 +106,7 @@ mod tests {
     #[test]
     fn excluding_custom_ignore_includes_git() {
         let mut strings: Vec<String> = vec![];
-        let regex_set = process_ignores(&test_logger(), &mut strings);
+        let regex_set = process_ignores(&mut strings).unwrap();
         assert!(!regex_set.is_match("something"));
         assert!(regex_set.is_match(".bindrsasdf"));
         assert!(regex_set.is_match(".git/something"));
@@ -173,7 +116,7 @@ mod tests {
     fn regex_strings_are_not_modified() {
         let mut strings: Vec<String> = vec![];
         strings.push("^something$".to_owned());
-        let regex_set = process_ignores(&test_logger(), &mut strings);
+        let regex_set = process_ignores(&mut strings).unwrap();
         assert!(regex_set.is_match("something"));
         assert!(!regex_set.is_match("somethin"));
         assert!(!regex_set.is_match("somethingg"));
@@ -184,13 +127,34 @@ mod tests {
     fn non_regex_strings_are_modified() {
         let mut strings: Vec<String> = vec![];
         strings.push("something".to_owned());
-        let regex_set = process_ignores(&test_logger(), &mut strings);
+        let regex_set = process_ignores(&mut strings).unwrap();
         assert!(regex_set.is_match("something"));
         assert!(!regex_set.is_match("somethin"));
         assert!(!regex_set.is_match("somethingg"));
         assert!(regex_set.is_match("something/somethingelse"));
     }
 
+    #[test]
+    fn temp_files_are_always_ignored() {
+        let mut strings: Vec<String> = vec![];
+        strings.push("^something$".to_owned());
+        let regex_set = process_ignores(&mut strings).unwrap();
+        assert!(regex_set.is_match(".a.txt.bindrs-tmp"));
+        assert!(regex_set.is_match("src/.main.rs.bindrs-tmp"));
+        assert!(!regex_set.is_match("src/main.rs"));
+        assert!(!regex_set.is_match("src.bindrs-tmp/main.rs"));
+    }
+
+    #[test]
+    fn invalid_regex_is_a_config_error() {
+        let mut strings: Vec<String> = vec![];
+        strings.push("^(unclosed$".to_owned());
+        match process_ignores(&mut strings) {
+            Err(BindrsError::Config(_)) => (),
+            _ => panic!("expected a config error"),
+        }
+    }
+
     #[test]
     fn resolve_path_canonicalize_correctly() {
         let mut path = current_dir().unwrap();
@@ -200,25 +164,4 @@ mod tests {
             resolve_path("./src/processors/../../src").unwrap()
         );
     }
-
-    #[test]
-    fn versions_are_compatible_correctly_matches() {
-        let base_version = Version::parse("1.2.3").unwrap();
-        assert!(versions_are_compatible(
-            &Version::parse("1.2.3").unwrap(),
-            &base_version,
-        ));
-        assert!(versions_are_compatible(
-            &Version::parse("1.2.0").unwrap(),
-            &base_version,
-        ));
-        assert!(!versions_are_compatible(
-            &Version::parse("1.3.3").unwrap(),
-            &base_version,
-        ));
-        assert!(!versions_are_compatible(
-            &Version::parse("2.2.3").unwrap(),
-            &base_version,
-        ));
-    }
 }
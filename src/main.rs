@@ -22,19 +22,37 @@ extern crate byteorder;
 extern crate filetime;
 extern crate time;
 extern crate tempdir;
-extern crate semver;
+extern crate sha2;
+extern crate flate2;
+extern crate xattr;
+extern crate nix;
+extern crate snow;
 
 use clap::{App, ArgMatches};
-use slog::Drain;
+use error::BindrsError;
+use slog::{Drain, Logger};
 use std::fs::{self, OpenOptions};
 use std::path::Path;
-use std::sync::Mutex;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use structs::codec::Codec;
+use structs::handshake::Session;
+use structs::owner::OwnerMap;
+use structs::remote_info::RemoteInfo;
+use structs::tcp::{self, TcpRemote};
+use structs::unix_socket::SocketRemote;
+use structs::xattrs::XattrNamespaces;
 
+mod error;
 mod master;
 mod slave;
 mod helpers;
 mod processors;
 mod structs;
+#[cfg(test)]
+mod test_support;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -47,43 +65,115 @@ fn main() {
         run_master(sub_m);
     } else if let Some(sub_m) = m.subcommand_matches("slave") {
         run_slave(sub_m);
+    } else if let Some(sub_m) = m.subcommand_matches("listen") {
+        run_listener(sub_m);
     }
 }
 
 fn run_master(m: &ArgMatches) {
-    #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
-    // Unwrap is safe - required by clap
-    let base_dir = get_base_dir(m.value_of("base_dir").unwrap());
+    let (base_dir, session) = get_master_args(m).unwrap_or_else(|e| print_error_and_exit(&e));
     #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
     let remote_dir = m.value_of("remote_dir").unwrap(); // Unwrap is safe - required by clap
-    let remote_port = m.value_of("port");
     let verbose_mode = m.is_present("verbose");
     let mut ignore_strings = get_ignore_strings(m);
 
-    let log = setup_log(&base_dir, verbose_mode, true);
+    let log = setup_log(&base_dir, verbose_mode, "master").unwrap_or_else(|e| print_error_and_exit(&e));
     info!(log, "Starting BindRS");
 
-    master::run(
-        &log,
-        &base_dir,
-        remote_dir,
-        remote_port,
-        &mut ignore_strings,
-        verbose_mode,
-    )
+    let result = if let Some(remote) = TcpRemote::parse(remote_dir) {
+        get_key(m, "A --key-file is needed to sync with a tcp:// remote").and_then(|key| {
+            master::run_tcp(&log, &base_dir, &remote, &key, &mut ignore_strings, session)
+        })
+    } else if let Some(remote) = SocketRemote::parse(remote_dir) {
+        master::run_socket(&log, &base_dir, &remote, &mut ignore_strings, session)
+    } else {
+        get_remote_info(m, remote_dir).and_then(|remote_info| {
+            master::run(
+                &log,
+                &base_dir,
+                &remote_info,
+                &mut ignore_strings,
+                session,
+                verbose_mode,
+            )
+        })
+    };
+    if let Err(e) = result {
+        log_error_and_exit(&log, &e);
+    }
 }
 
 fn run_slave(m: &ArgMatches) {
     #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
     // Unwrap is safe - required by clap
-    let base_dir = get_base_dir(m.value_of("base_dir").unwrap());
+    let base_dir = get_base_dir(m.value_of("base_dir").unwrap()).unwrap_or_else(|e| print_error_and_exit(&e));
     let mut ignore_strings = get_ignore_strings(m);
     let verbose_mode = m.is_present("verbose");
+    let socket_path = m.value_of("socket");
 
-    let log = setup_log(&base_dir, verbose_mode, false);
+    // A socket slave is started by hand rather than by a master, so it logs to the terminal too
+    let mode = if socket_path.is_some() { "socket" } else { "slave" };
+    let log = setup_log(&base_dir, verbose_mode, mode).unwrap_or_else(|e| print_error_and_exit(&e));
     info!(log, "Starting BindRS");
 
-    slave::run(&log, &base_dir, &mut ignore_strings)
+    let result = match socket_path {
+        Some(path) => slave::listen_socket(&log, &base_dir, path, &ignore_strings),
+        None => slave::run(&log, &base_dir, &mut ignore_strings),
+    };
+    if let Err(e) = result {
+        log_error_and_exit(&log, &e);
+    }
+}
+
+fn run_listener(m: &ArgMatches) {
+    #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
+    // Unwrap is safe - required by clap
+    let base_dir = get_base_dir(m.value_of("base_dir").unwrap()).unwrap_or_else(|e| print_error_and_exit(&e));
+    let key = get_key(m, "A --key-file is needed to listen").unwrap_or_else(|e| print_error_and_exit(&e));
+    #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
+    // Unwraps are safe - required and defaulted by clap
+    let address = format!("{}:{}", m.value_of("address").unwrap(), m.value_of("port").unwrap());
+    let ignore_strings = get_ignore_strings(m);
+    let verbose_mode = m.is_present("verbose");
+
+    let log = setup_log(&base_dir, verbose_mode, "listener").unwrap_or_else(|e| print_error_and_exit(&e));
+    info!(log, "Starting BindRS");
+
+    if let Err(e) = slave::listen(&log, &base_dir, &address, &key, &ignore_strings) {
+        log_error_and_exit(&log, &e);
+    }
+}
+
+#[cfg_attr(feature = "clippy", allow(print_stdout))]
+fn print_error_and_exit(e: &BindrsError) -> ! {
+    println!("{}", e);
+    exit(e.exit_code());
+}
+
+fn log_error_and_exit(log: &Logger, e: &BindrsError) -> ! {
+    error!(log, "{}", e);
+    // Give the log drains a moment to write the error out
+    thread::sleep(Duration::from_millis(500));
+    exit(e.exit_code());
+}
+
+fn get_master_args(m: &ArgMatches) -> Result<(String, Session), BindrsError> {
+    #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
+    // Unwrap is safe - required by clap
+    let base_dir = get_base_dir(m.value_of("base_dir").unwrap())?;
+    let session = Session {
+        codec: get_codec(m),
+        delta: true,
+        preserve_symlinks: !m.is_present("follow_symlinks"),
+        preserve_atimes: m.is_present("atimes"),
+        xattr_namespaces: get_xattr_namespaces(m),
+        preserve_owners: m.is_present("owners"),
+        owner_map: Arc::new(get_owner_map(m)?),
+        preserve_hard_links: m.is_present("hard_links"),
+        initial_sync: false,
+        timeout_secs: get_timeout_secs(m)?,
+    };
+    Ok((base_dir, session))
 }
 
 fn get_ignore_strings(m: &ArgMatches) -> Vec<String> {
@@ -93,57 +183,113 @@ fn get_ignore_strings(m: &ArgMatches) -> Vec<String> {
     }
 }
 
-fn get_base_dir(base_dir: &str) -> String {
-    helpers::resolve_path(base_dir).unwrap_or_else(|| {
-        helpers::print_error_and_exit("failed to find base directory");
-        "".to_owned()
-    })
+fn get_codec(m: &ArgMatches) -> Codec {
+    #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
+    // Unwrap is safe - defaulted and restricted to known codecs by clap
+    Codec::from_name(m.value_of("compression").unwrap()).unwrap()
+}
+
+fn get_xattr_namespaces(m: &ArgMatches) -> XattrNamespaces {
+    if !m.is_present("xattrs") {
+        return XattrNamespaces::default();
+    }
+
+    #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
+    // Unwraps are safe - defaulted and restricted to known namespaces by clap
+    XattrNamespaces::from_names(m.values_of("xattr_namespaces").unwrap()).unwrap()
+}
+
+fn get_owner_map(m: &ArgMatches) -> Result<OwnerMap, BindrsError> {
+    let mut owner_map = OwnerMap::default();
+    if let Some(users) = m.values_of("map_user") {
+        for user in users {
+            owner_map.add_user(user)?;
+        }
+    }
+    if let Some(groups) = m.values_of("map_group") {
+        for group in groups {
+            owner_map.add_group(group)?;
+        }
+    }
+    Ok(owner_map)
+}
+
+fn get_timeout_secs(m: &ArgMatches) -> Result<u64, BindrsError> {
+    #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
+    // Unwrap is safe - defaulted by clap
+    match m.value_of("timeout").unwrap().parse() {
+        Ok(secs) if secs > 0 => Ok(secs),
+        _ => Err(BindrsError::Config(
+            "Timeout must be a positive number of seconds".to_owned(),
+        )),
+    }
+}
+
+fn get_remote_info(m: &ArgMatches, remote_dir: &str) -> Result<RemoteInfo, BindrsError> {
+    #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
+    // Unwrap is safe - defaulted by clap
+    let ssh_command = m.value_of("ssh_command").unwrap();
+    if ssh_command.trim().is_empty() {
+        return Err(BindrsError::Config("SSH command can't be empty".to_owned()));
+    }
+    let ssh_options: Vec<String> = match m.values_of("ssh_option") {
+        Some(options) => options.map(|option| option.to_owned()).collect(),
+        None => vec![],
+    };
+    Ok(RemoteInfo::build(remote_dir, m.value_of("port"), ssh_command, &ssh_options))
+}
+
+fn get_key(m: &ArgMatches, missing: &str) -> Result<Vec<u8>, BindrsError> {
+    match m.value_of("key_file") {
+        Some(path) => tcp::load_key(path),
+        None => Err(BindrsError::Config(missing.to_owned())),
+    }
+}
+
+fn get_base_dir(base_dir: &str) -> Result<String, BindrsError> {
+    helpers::resolve_path(base_dir).ok_or_else(|| BindrsError::Config("failed to find base directory".to_owned()))
 }
 
-fn setup_log(base_dir: &str, verbose_mode: bool, master_mode: bool) -> slog::Logger {
+/// Logs to `.bindrs/bindrs.log`, and to the terminal too unless running as a slave.
+fn setup_log(base_dir: &str, verbose_mode: bool, mode: &'static str) -> Result<Logger, BindrsError> {
     let mut path_buf = Path::new(base_dir).to_path_buf();
     path_buf.push(".bindrs");
 
-    match fs::create_dir_all(path_buf.as_path()) {
-        Ok(_) => (),
-        Err(_) => helpers::print_error_and_exit("Failed to create .bindrs directory!"),
-    }
+    fs::create_dir_all(path_buf.as_path()).map_err(|_| {
+        BindrsError::Config("Failed to create .bindrs directory!".to_owned())
+    })?;
 
     path_buf.push("bindrs");
     path_buf.set_extension("log");
 
-    let wrapped_file = OpenOptions::new()
+    let file = OpenOptions::new()
         .create(true)
         .write(true)
         .truncate(true)
-        .open(path_buf.as_path());
-
-    if let Ok(file) = wrapped_file {
-        let level = if verbose_mode {
-            slog::Level::Debug
-        } else {
-            slog::Level::Info
-        };
-
-        let file_decorator = slog_term::PlainSyncDecorator::new(file);
-        let file_drain = slog_term::FullFormat::new(file_decorator).build();
-        let file_drain = slog::LevelFilter::new(file_drain, level);
-
-        if master_mode {
-            let term_decorator = slog_term::TermDecorator::new().build();
-            let term_drain = Mutex::new(slog_term::CompactFormat::new(term_decorator).build()).fuse();
-            let term_drain = slog::LevelFilter::new(term_drain, level);
-            let drain = slog::Duplicate::new(file_drain, term_drain);
-
-            slog::Logger::root(drain.fuse(), o!("version" => VERSION, "mode" => "master"))
-        } else {
-            slog::Logger::root(
-                file_drain.fuse(),
-                o!("version" => VERSION, "mode" => "slave"),
-            )
-        }
+        .open(path_buf.as_path())
+        .map_err(|_| BindrsError::Config("Failed to create log file.".to_owned()))?;
+
+    let level = if verbose_mode {
+        slog::Level::Debug
+    } else {
+        slog::Level::Info
+    };
+
+    let file_decorator = slog_term::PlainSyncDecorator::new(file);
+    let file_drain = slog_term::FullFormat::new(file_decorator).build();
+    let file_drain = slog::LevelFilter::new(file_drain, level);
+
+    if mode != "slave" {
+        let term_decorator = slog_term::TermDecorator::new().build();
+        let term_drain = Mutex::new(slog_term::CompactFormat::new(term_decorator).build()).fuse();
+        let term_drain = slog::LevelFilter::new(term_drain, level);
+        let drain = slog::Duplicate::new(file_drain, term_drain);
+
+        Ok(Logger::root(drain.fuse(), o!("version" => VERSION, "mode" => mode)))
     } else {
-        helpers::print_error_and_exit("Failed to create log file.");
-        panic!(); // For compilation
+        Ok(Logger::root(
+            file_drain.fuse(),
+            o!("version" => VERSION, "mode" => mode),
+        ))
     }
 }
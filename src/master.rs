@@ -1,32 +1,374 @@
+use error::BindrsError;
 use helpers;
 use processors::{executor, rsync};
+use regex::RegexSet;
+use slave;
 use slog::Logger;
-use std::{time, thread};
-use std::process::{Stdio, ChildStdout, ChildStdin};
+use std::path::Path;
+use std::{cmp, time, thread};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::process::{Child, Stdio, ChildStdout, ChildStdin};
+use std::sync::mpsc::{self, Receiver};
+use structs::bound_file::FileAction;
+use structs::codec::Codec;
+use structs::connection::{self, HANDSHAKE_TIMEOUT_SECS};
+use structs::frame::{Frame, FrameReader, FrameWriter};
+use structs::handshake::{Handshake, Session};
+use structs::local_changes::{DirtyPaths, LocalChanges};
+use structs::manifest::{self, Manifest};
+use structs::pipe::pipe;
 use structs::remote_info::RemoteInfo;
+use structs::tcp::{self, TcpRemote};
+use structs::unix_socket::{self, SocketRemote};
+
+// A remote scanning a large tree can take a while to send its manifest, but not this long
+const MANIFEST_TIMEOUT_SECS: u64 = 10 * 60;
+
+// Reconnect attempts start this far apart and back off up to the maximum
+const RECONNECT_MIN_DELAY_SECS: u64 = 1;
+const RECONNECT_MAX_DELAY_SECS: u64 = 60;
 
 pub fn run(
     log: &Logger,
     base_dir: &str,
-    remote_dir: &str,
-    port: Option<&str>,
+    remote_info: &RemoteInfo,
     ignore_strings: &mut Vec<String>,
+    requested_session: Session,
+    verbose_mode: bool,
+) -> Result<(), BindrsError> {
+    let ignores = helpers::process_ignores(ignore_strings)?;
+    if !remote_info.is_remote {
+        return run_local(log, base_dir, remote_info, &ignores, requested_session, verbose_mode);
+    }
+
+    validate_remote_directory(remote_info)?;
+    let bindrs_path = validate_remote_bindrs(log, remote_info, false)?;
+    let local_changes = LocalChanges::watch(base_dir, &ignores)?;
+    rsync::run(log, base_dir, remote_info, &ignores, &requested_session)?;
+
+    let mut slave: Option<Child> = None;
+    let mut connect = || {
+        // A slave left over from a dropped connection would keep syncing alongside the new one
+        if let Some(mut old_slave) = slave.take() {
+            old_slave.kill().and_then(|_| old_slave.wait()).ok();
+        }
+        let (child, remote_reader, remote_writer) = start_remote_slave(
+            log,
+            remote_info,
+            &bindrs_path,
+            ignore_strings,
+            verbose_mode,
+        )?;
+        slave = Some(child);
+        Ok((remote_reader, remote_writer))
+    };
+    let connection = connect()?;
+    keep_connected(log, base_dir, &ignores, &local_changes, &requested_session, connection, connect)
+}
+
+/// Syncs two local directories, running the slave's half on a thread of this process.
+fn run_local(
+    log: &Logger,
+    base_dir: &str,
+    remote_info: &RemoteInfo,
+    ignores: &RegexSet,
+    mut requested_session: Session,
     verbose_mode: bool,
-) {
-    let ignores = helpers::process_ignores(log, ignore_strings);
-    let remote_info = RemoteInfo::build(remote_dir, port);
-
-    validate_remote_directory(log, &remote_info);
-    let bindrs_path = validate_remote_bindrs(log, &remote_info, false);
-    rsync::run(log, base_dir, &remote_info, &ignores);
-    let (remote_reader, remote_writer) = start_remote_slave(
+) -> Result<(), BindrsError> {
+    let remote_dir = helpers::resolve_path(&remote_info.path)
+        .filter(|dir| Path::new(dir).is_dir())
+        .ok_or_else(|| {
+            BindrsError::Remote("Remote directory does not exist, please create it".to_owned())
+        })?;
+    let local_changes = LocalChanges::watch(base_dir, ignores)?;
+    rsync::run(log, base_dir, remote_info, ignores, &requested_session)?;
+
+    // The slave half logs to its own directory, as a spawned slave would
+    let slave_log = ::setup_log(&remote_dir, verbose_mode, "slave")?;
+    let (remote_reader, slave_writer) = pipe();
+    let (slave_reader, remote_writer) = pipe();
+    let slave_ignores = ignores.clone();
+    let (slave_tx, slave_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let result = slave::serve(&slave_log, &remote_dir, slave_ignores, slave_reader, slave_writer);
+        slave_tx.send(result).unwrap_or_default();
+    });
+
+    // Nothing leaves memory, so compressing would only cost time
+    requested_session.codec = Codec::None;
+    let result = open_session(
         log,
-        &remote_info,
-        &bindrs_path,
-        ignore_strings,
-        verbose_mode,
-    );
-    executor::start(log, base_dir, ignores, remote_reader, remote_writer);
+        base_dir,
+        ignores,
+        &local_changes,
+        remote_reader,
+        remote_writer,
+        &requested_session,
+        false,
+    ).and_then(|opened| run_session(log, base_dir, ignores, opened));
+
+    // The master only sees the pipe close when the slave half fails, so pass on why it did
+    match (result, slave_rx.recv_timeout(time::Duration::from_secs(1))) {
+        (Err(BindrsError::Disconnected(_)), Ok(Err(e))) => Err(e),
+        (result, _) => result,
+    }
+}
+
+/// Syncs with a `bindrs listen` remote over an encrypted TCP connection.
+pub fn run_tcp(
+    log: &Logger,
+    base_dir: &str,
+    remote: &TcpRemote,
+    key: &[u8],
+    ignore_strings: &mut Vec<String>,
+    mut requested_session: Session,
+) -> Result<(), BindrsError> {
+    let ignores = helpers::process_ignores(ignore_strings)?;
+    let local_changes = LocalChanges::watch(base_dir, &ignores)?;
+    let mut last = None;
+    let mut connect = || {
+        connection::replace(&mut last, || {
+            info!(log, "Connecting to {}", remote.address);
+            let (remote_reader, remote_writer) = tcp::connect(remote, key, ignore_strings)?;
+            Ok((remote_reader.try_clone_stream()?, remote_reader, remote_writer))
+        })
+    };
+    let connection = connect()?;
+
+    // Without rsync on the other end, the remote is mirrored from its manifest instead
+    requested_session.initial_sync = true;
+    keep_connected(log, base_dir, &ignores, &local_changes, &requested_session, connection, connect)
+}
+
+/// Attaches to a `bindrs slave --socket` running on this machine.
+pub fn run_socket(
+    log: &Logger,
+    base_dir: &str,
+    remote: &SocketRemote,
+    ignore_strings: &mut Vec<String>,
+    mut requested_session: Session,
+) -> Result<(), BindrsError> {
+    let ignores = helpers::process_ignores(ignore_strings)?;
+    let local_changes = LocalChanges::watch(base_dir, &ignores)?;
+    let mut last = None;
+    let mut connect = || {
+        connection::replace(&mut last, || {
+            info!(log, "Attaching to slave at {}", remote.path);
+            let (remote_reader, remote_writer) = unix_socket::connect(remote, ignore_strings)?;
+            Ok((connection::split(&remote_reader)?, remote_reader, remote_writer))
+        })
+    };
+    let connection = connect()?;
+
+    // The slave may have been running long before this master, so catch it up without rsync
+    requested_session.initial_sync = true;
+    keep_connected(log, base_dir, &ignores, &local_changes, &requested_session, connection, connect)
+}
+
+/// A session that has finished its handshake and is ready to sync.
+struct OpenSession<R: Read, W: Write> {
+    reader: FrameReader<R>,
+    writer: FrameWriter<W>,
+    session: Session,
+    initial_changes: Vec<(FileAction, String)>,
+    local_changes: Receiver<(FileAction, String)>,
+    dirty: DirtyPaths,
+}
+
+/// Runs sessions over `connection` and then whatever `connect` gives back after each one that
+/// ends by losing the connection. Anything else that ends a session ends the sync.
+#[cfg_attr(feature = "clippy", allow(too_many_arguments))]
+fn keep_connected<R, W, C>(
+    log: &Logger,
+    base_dir: &str,
+    ignores: &RegexSet,
+    local_changes: &LocalChanges,
+    requested_session: &Session,
+    connection: (R, W),
+    mut connect: C,
+) -> Result<(), BindrsError>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+    C: FnMut() -> Result<(R, W), BindrsError>,
+{
+    let (remote_reader, remote_writer) = connection;
+    let mut opened = open_session(
+        log,
+        base_dir,
+        ignores,
+        local_changes,
+        remote_reader,
+        remote_writer,
+        requested_session,
+        false,
+    )?;
+    loop {
+        match run_session(log, base_dir, ignores, opened) {
+            Err(BindrsError::Disconnected(e)) => warn!(log, "Lost connection to remote: {}", e),
+            result => return result,
+        }
+        opened = reconnect(log, base_dir, ignores, local_changes, requested_session, &mut connect)?;
+    }
+}
+
+/// Retries `connect`, backing off exponentially, until a session opens. Changes made on either
+/// side while disconnected are then reconciled from the remote's manifest.
+fn reconnect<R, W, C>(
+    log: &Logger,
+    base_dir: &str,
+    ignores: &RegexSet,
+    local_changes: &LocalChanges,
+    requested_session: &Session,
+    connect: &mut C,
+) -> Result<OpenSession<R, W>, BindrsError>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+    C: FnMut() -> Result<(R, W), BindrsError>,
+{
+    // Even a remote first synced by rsync has to be compared file by file now
+    let mut requested_session = requested_session.clone();
+    requested_session.initial_sync = true;
+
+    let mut delay = RECONNECT_MIN_DELAY_SECS;
+    loop {
+        info!(log, "Reconnecting in {} second/s", delay);
+        thread::sleep(time::Duration::from_secs(delay));
+
+        let opened = connect().and_then(|(remote_reader, remote_writer)| {
+            open_session(
+                log,
+                base_dir,
+                ignores,
+                local_changes,
+                remote_reader,
+                remote_writer,
+                &requested_session,
+                true,
+            )
+        });
+        match opened {
+            Ok(opened) => return Ok(opened),
+            Err(BindrsError::Remote(e)) |
+            Err(BindrsError::Disconnected(e)) => warn!(log, "Failed to reconnect: {}", e),
+            Err(e) => return Err(e),
+        }
+        delay = cmp::min(delay * 2, RECONNECT_MAX_DELAY_SECS);
+    }
+}
+
+#[cfg_attr(feature = "clippy", allow(too_many_arguments))]
+fn open_session<R: Read + Send + 'static, W: Write + Send + 'static>(
+    log: &Logger,
+    base_dir: &str,
+    ignores: &RegexSet,
+    local_changes: &LocalChanges,
+    remote_reader: R,
+    remote_writer: W,
+    requested_session: &Session,
+    reconnecting: bool,
+) -> Result<OpenSession<R, W>, BindrsError> {
+    let (remote_handshake, remote_reader) = read_remote_handshake(remote_reader)?;
+    info!(log, "Remote is {}", remote_handshake.describe());
+
+    let session = Session::negotiate(log, requested_session, &remote_handshake)?;
+    let mut remote_writer = FrameWriter::new(remote_writer);
+    remote_writer.send(&Frame::Handshake(Handshake::for_session(&session)))?;
+    remote_writer.flush()?;
+    remote_writer.set_codec(session.codec)?;
+
+    // Attached before the manifest is taken so nothing changed after it is missed
+    let (session_changes, dirty_paths) = local_changes.attach()?;
+    let dirty = local_changes.take_dirty()?;
+    let (initial_changes, remote_reader) = if session.initial_sync {
+        let exchanged = exchange_manifests(
+            log,
+            base_dir,
+            ignores,
+            remote_reader,
+            &mut remote_writer,
+            &dirty,
+            reconnecting,
+        );
+        // Until the remote has them, changes still to send have to win again after another drop
+        match exchanged {
+            Ok((ref changes, _)) => local_changes.mark_dirty(changes.iter().map(|&(_, ref path)| path.clone()))?,
+            Err(_) => local_changes.mark_dirty(dirty)?,
+        }
+        exchanged?
+    } else {
+        (vec![], remote_reader)
+    };
+
+    Ok(OpenSession {
+        reader: remote_reader,
+        writer: remote_writer,
+        session,
+        initial_changes,
+        local_changes: session_changes,
+        dirty: dirty_paths,
+    })
+}
+
+/// Compares the remote's manifest with the local directory and tells the remote what to send
+/// back, returning the changes to send it.
+fn exchange_manifests<R: Read + Send + 'static, W: Write>(
+    log: &Logger,
+    base_dir: &str,
+    ignores: &RegexSet,
+    remote_reader: FrameReader<R>,
+    remote_writer: &mut FrameWriter<W>,
+    dirty: &HashSet<String>,
+    reconnecting: bool,
+) -> Result<(Vec<(FileAction, String)>, FrameReader<R>), BindrsError> {
+    info!(log, "Comparing local files with remote");
+    // Scanned while the remote scans its own side, so neither waits on the other for long
+    let local_manifest = Manifest::scan(base_dir, ignores);
+    let (remote_manifest, remote_reader) = match recv_within(remote_reader, MANIFEST_TIMEOUT_SECS, Manifest::recv) {
+        Some(received) => received?,
+        None => {
+            return Err(BindrsError::Disconnected(format!(
+                "Remote did not send its manifest within {} seconds",
+                MANIFEST_TIMEOUT_SECS
+            )))
+        }
+    };
+
+    let (send, pull) = if reconnecting {
+        if local_manifest.looks_wiped(&remote_manifest) {
+            warn!(log, "Remote is missing most of the local files, sending them back instead of deleting them here");
+        }
+        local_manifest.reconcile(&remote_manifest, dirty)
+    } else {
+        (local_manifest.changes_to_mirror(&remote_manifest), vec![])
+    };
+    if !pull.is_empty() {
+        info!(log, "Asking remote for {} change/s made while disconnected", pull.len());
+    }
+    manifest::send_pull(pull, remote_writer)?;
+    remote_writer.flush()?;
+    Ok((send, remote_reader))
+}
+
+fn run_session<R: Read + Send + 'static, W: Write + Send + 'static>(
+    log: &Logger,
+    base_dir: &str,
+    ignores: &RegexSet,
+    opened: OpenSession<R, W>,
+) -> Result<(), BindrsError> {
+    executor::start(
+        log,
+        base_dir,
+        ignores,
+        opened.reader,
+        opened.writer,
+        opened.session,
+        opened.initial_changes,
+        opened.local_changes,
+        Some(opened.dirty),
+    )
 }
 
 fn start_remote_slave(
@@ -35,7 +377,7 @@ fn start_remote_slave(
     bindrs_path: &str,
     ignores: &mut Vec<String>,
     verbose_mode: bool,
-) -> (ChildStdout, ChildStdin) {
+) -> Result<(Child, ChildStdout, ChildStdin), BindrsError> {
     info!(log, "Starting remote slave");
     let ignore_args: Vec<String> = ignores
         .iter()
@@ -53,96 +395,147 @@ fn start_remote_slave(
         cmd += " -v"
     }
 
-    if let Ok(mut child) = remote_info
+    let mut child = remote_info
         .generate_command(&mut remote_info.base_command(&cmd), &cmd)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()
-    {
-        thread::sleep(time::Duration::new(1, 0));
-        #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
-        let c_stdout = child.stdout.take().unwrap(); // Unwrap is safe - provided in child spawn
-        #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
-        let c_stdin = child.stdin.take().unwrap(); // Unwrap is safe - provided in child spawn
-
-        (c_stdout, c_stdin)
-    } else {
-        helpers::log_error_and_exit(log, "Failed to spawn a child");
-        panic!(); // For compilation
+        .map_err(|e| BindrsError::Remote(format!("Failed to spawn a child: {}", e)))?;
+
+    #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
+    let c_stdout = child.stdout.take().unwrap(); // Unwrap is safe - provided in child spawn
+    #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
+    let c_stdin = child.stdin.take().unwrap(); // Unwrap is safe - provided in child spawn
+
+    Ok((child, c_stdout, c_stdin))
+}
+
+/// Runs `receive` on `reader` and hands the reader back with what it got, or `None` if it
+/// didn't finish within `timeout_secs`. Reading blocks until the remote speaks, so it's done on
+/// a thread that is left behind if it never does.
+fn recv_within<R, T, F>(
+    mut reader: FrameReader<R>,
+    timeout_secs: u64,
+    receive: F,
+) -> Option<Result<(T, FrameReader<R>), BindrsError>>
+where
+    R: Read + Send + 'static,
+    T: Send + 'static,
+    F: FnOnce(&mut FrameReader<R>) -> Result<T, BindrsError> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let received = receive(&mut reader).map(|received| (received, reader));
+        tx.send(received).unwrap_or_default();
+    });
+
+    match rx.recv_timeout(time::Duration::from_secs(timeout_secs)) {
+        Ok(received) => Some(received),
+        Err(mpsc::RecvTimeoutError::Timeout) => None,
+        Err(mpsc::RecvTimeoutError::Disconnected) => Some(Err(BindrsError::Internal(
+            "Remote reader stopped unexpectedly".to_owned(),
+        ))),
     }
 }
 
-fn validate_remote_directory(log: &Logger, remote_info: &RemoteInfo) {
-    log_error_and_exit_on_bad_command_output(
-        log,
+fn read_remote_handshake<R: Read + Send + 'static>(remote_reader: R) -> Result<(Handshake, FrameReader<R>), BindrsError> {
+    let recv_frame = |reader: &mut FrameReader<R>| reader.recv().map(|(_, frame)| frame);
+    match recv_within(FrameReader::new(remote_reader), HANDSHAKE_TIMEOUT_SECS, recv_frame) {
+        Some(Ok((Frame::Handshake(handshake), reader))) => Ok((handshake, reader)),
+        Some(Ok(_)) => Err(BindrsError::Protocol(
+            "Remote BindRS sent data before its handshake".to_owned(),
+        )),
+        Some(Err(_)) => Err(BindrsError::Remote(
+            "Remote BindRS exited before sending a handshake".to_owned(),
+        )),
+        None => Err(BindrsError::Remote(format!(
+            "Remote BindRS did not send a handshake, it may be older than {}. \
+             Please update older version to match newer version.",
+            ::VERSION
+        ))),
+    }
+}
+
+fn validate_remote_directory(remote_info: &RemoteInfo) -> Result<(), BindrsError> {
+    error_on_bad_command_output(
         remote_info,
         &format!("test -d {} || echo 'bad'", remote_info.path),
         &["bad".to_string()],
         false,
         "Remote directory does not exist, please create it",
-    );
+    )
 }
 
-fn validate_remote_bindrs(log: &Logger, remote_info: &RemoteInfo, download_attempted: bool) -> String {
-    let bindrs_path = match remote_info.check_cmd_output(
-        log,
-        "which bindrs",
-        &["bindrs not found".to_string(), "".to_string()],
-        false,
-    ) {
-        Ok(path) => path,
-        Err(_) => {
-            if let Ok(path) = remote_info.check_cmd_output(
-                log,
-                &format!("PATH={}/.bindrs:$PATH which bindrs", remote_info.path),
-                &["bindrs not found".to_string(), "".to_string()],
-                false,
-            )
-            {
-                path
-            } else {
-                if !download_attempted {
-                    warn!(
-                        log,
-                        "BindRS missing on remote, attempting to download to .bindrs dir"
-                    );
-                    if helpers::download_bindrs(log, remote_info) {
-                        return validate_remote_bindrs(log, remote_info, true);
-                    }
-                }
-
-                helpers::log_error_and_exit(
-                    log,
-                    "Please install BindRS on the remote machine and add it to the path",
-                );
-                panic!() // For compilation
-            }
-        }
-    };
+fn validate_remote_bindrs(log: &Logger, remote_info: &RemoteInfo, download_attempted: bool) -> Result<String, BindrsError> {
+    let not_found = ["bindrs not found".to_string(), "".to_string()];
+    if let Some(path) = remote_info.check_cmd_output("which bindrs", &not_found, false)? {
+        return Ok(path);
+    }
 
-    match remote_info.get_cmd_output(&format!("{} --version", bindrs_path)) {
-        Ok(mut output) => helpers::compare_version_strings(log, ::VERSION, &output.split_off(7)),
-        Err(e) => {
-            helpers::log_error_and_exit(
-                log,
-                &format!("Failed to get BindRS version from remote: {}", e),
-            )
+    let cmd = format!("PATH={}/.bindrs:$PATH which bindrs", remote_info.path);
+    if let Some(path) = remote_info.check_cmd_output(&cmd, &not_found, false)? {
+        return Ok(path);
+    }
+
+    if !download_attempted {
+        warn!(
+            log,
+            "BindRS missing on remote, attempting to download to .bindrs dir"
+        );
+        if helpers::download_bindrs(log, remote_info) {
+            return validate_remote_bindrs(log, remote_info, true);
         }
-    };
+    }
 
-    bindrs_path
+    Err(BindrsError::Remote(
+        "Please install BindRS on the remote machine and add it to the path".to_owned(),
+    ))
 }
 
-fn log_error_and_exit_on_bad_command_output(
-    log: &Logger,
+fn error_on_bad_command_output(
     remote_info: &RemoteInfo,
     cmd: &str,
     wanted_output: &[String],
     match_output: bool,
     bad_output_error: &str,
-) {
-    match remote_info.check_cmd_output(log, cmd, wanted_output, match_output) {
-        Ok(_) => (),
-        Err(_) => helpers::log_error_and_exit(log, bad_output_error),
+) -> Result<(), BindrsError> {
+    match remote_info.check_cmd_output(cmd, wanted_output, match_output)? {
+        Some(_) => Ok(()),
+        None => Err(BindrsError::Remote(bad_output_error.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::Discard;
+    use std::fs;
+    use std::process::Command;
+    use test_support::{eventually, test_dir};
+
+    #[test]
+    fn local_directories_are_mirrored_both_ways() {
+        // The initial sync is rsync's, which not every machine has
+        if Command::new("rsync").arg("--version").output().is_err() {
+            return;
+        }
+        let (_local, local_dir) = test_dir("bindrs-master-local");
+        let (_remote, remote_dir) = test_dir("bindrs-master-remote");
+        fs::write(format!("{}/before", local_dir), b"before").unwrap();
+
+        let base_dir = local_dir.clone();
+        let remote_info = RemoteInfo::build(&remote_dir, None, "ssh", &[]);
+        thread::spawn(move || {
+            let ignores = helpers::process_ignores(&mut vec![]).unwrap();
+            let session = Session::from_handshake(&Handshake::local());
+            run_local(&Logger::root(Discard, o!()), &base_dir, &remote_info, &ignores, session, false)
+        });
+
+        let synced = |dir: &str, name: &str| fs::read(format!("{}/{}", dir, name)).ok() == Some(name.as_bytes().to_vec());
+        assert!(eventually(|| synced(&remote_dir, "before")));
+        fs::write(format!("{}/after", local_dir), b"after").unwrap();
+        assert!(eventually(|| synced(&remote_dir, "after")));
+        fs::write(format!("{}/back", remote_dir), b"back").unwrap();
+        assert!(eventually(|| synced(&local_dir, "back")));
     }
 }
@@ -1,205 +1,801 @@
+use error::BindrsError;
 use helpers;
 use regex::RegexSet;
 use slog::Logger;
-use std::io::{Read, Write, BufWriter, BufReader};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
 use std::marker::Send;
+use std::mem;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{self, TryRecvError, Receiver};
+use std::sync::mpsc::{self, TryRecvError, Receiver, Sender};
 use std::thread::{self, sleep};
-use std::time::Duration;
-use structs::bound_file::{BoundFile, FileAction};
-use structs::watcher::BindrsWatcher;
+use std::time::{Duration, Instant};
+use structs::bound_file::{check_path, is_symlink, BoundFile, FileAction};
+use structs::delta::Signatures;
+use structs::frame::{Frame, FrameReader, FrameWriter};
+use structs::handshake::Session;
+use structs::hard_links::HardLinks;
+use structs::local_changes::DirtyPaths;
+use structs::sparse;
+use structs::watcher::walk_children;
 use time;
 
-type WatchLock = Arc<Mutex<Vec<(String, i64, i32)>>>;
+type WatchLock = Arc<Mutex<LocalFs>>;
+type SharedWriter<W> = Arc<Mutex<FrameWriter<W>>>;
 
+struct LocalFs {
+    // Files just written from the remote, so the watcher doesn't send them straight back
+    recent_files: Vec<(String, i64, i32)>,
+    // Only kept up to date when hard links are synced
+    hard_links: HardLinks,
+    // Only kept by a master, to know what changed here if the connection drops
+    dirty: Option<DirtyPaths>,
+    // Files whose delta waits on signatures from the remote
+    awaiting_signatures: HashSet<String>,
+    // Changes the remote hasn't acknowledged yet, keyed by the sequence number of their header
+    unconfirmed: BTreeMap<u64, Unconfirmed>,
+}
+
+/// Files the remote asked for, sent from their own thread so the listener keeps reading while
+/// they wait on the connection.
+enum Reply {
+    Delta(String, Option<Signatures>),
+    Retry(Unconfirmed),
+}
+
+struct Unconfirmed {
+    path: String,
+    action: FileAction,
+    attempts: u32,
+}
+
+// Give up on a change after this many failed retries rather than looping forever
+const MAX_RETRIES: u32 = 3;
+
+// How long a burst of local changes may keep collecting into one batch before it's sent
+const BATCH_TIME_BUDGET_MS: u64 = 50;
+
+// Files smaller than this are cheaper to resend than to diff over a round trip
+const DELTA_MIN_SIZE: u64 = 64 * 1024;
+
+/// Syncs until the connection fails. `initial_changes` are sent before any that arrive on
+/// `local_changes` from the caller's watcher, and paths are cleared from `dirty` once both
+/// sides have them.
+#[cfg_attr(feature = "clippy", allow(too_many_arguments))]
 pub fn start<R: Read + Send + 'static, W: Write + Send + 'static>(
     log: &Logger,
     base_dir: &str,
-    ignores: RegexSet,
-    reader: R,
-    writer: W,
-) {
-    let lock: WatchLock = Arc::new(Mutex::new(vec![]));
+    ignores: &RegexSet,
+    reader: FrameReader<R>,
+    writer: FrameWriter<W>,
+    session: Session,
+    initial_changes: Vec<(FileAction, String)>,
+    local_changes: Receiver<(FileAction, String)>,
+    dirty: Option<DirtyPaths>,
+) -> Result<(), BindrsError> {
+    let hard_links = if session.preserve_hard_links {
+        HardLinks::scan(base_dir, ignores)
+    } else {
+        HardLinks::default()
+    };
+    let lock: WatchLock = Arc::new(Mutex::new(LocalFs {
+        recent_files: vec![],
+        hard_links,
+        dirty,
+        awaiting_signatures: HashSet::new(),
+        unconfirmed: BTreeMap::new(),
+    }));
     let lock_clone = lock.clone();
 
     let sync_count: Arc<Mutex<(u32, u32)>> = Arc::new(Mutex::new((0, 0)));
+    let writer: SharedWriter<W> = Arc::new(Mutex::new(writer));
+    let (outbox_tx, outbox_rx) = mpsc::channel();
+    let (replies_tx, replies_rx) = mpsc::channel();
+    let (error_tx, error_rx) = mpsc::channel();
 
     let base_dir_clone = base_dir.to_owned();
     let log_clone = log.clone();
     let sync_count_clone = sync_count.clone();
-    let child_1 = thread::spawn(move || {
+    let writer_clone = writer.clone();
+    let session_clone = session.clone();
+    spawn_reporting(&error_tx, move || {
         run_local_watcher(
             &log_clone,
             &base_dir_clone,
-            &ignores,
-            writer,
+            &session_clone,
+            initial_changes,
+            &local_changes,
+            &writer_clone,
             &lock_clone,
             &sync_count_clone,
-        );
+        )
+    });
+
+    let base_dir_clone = base_dir.to_owned();
+    let ignores_clone = ignores.clone();
+    let log_clone = log.clone();
+    let sync_count_clone = sync_count.clone();
+    let writer_clone = writer.clone();
+    let session_clone = session.clone();
+    let lock_clone = lock.clone();
+    spawn_reporting(&error_tx, move || {
+        run_replies(
+            &log_clone,
+            &base_dir_clone,
+            &ignores_clone,
+            &session_clone,
+            &replies_rx,
+            &writer_clone,
+            &lock_clone,
+            &sync_count_clone,
+        )
     });
 
+    spawn_reporting(&error_tx, move || run_outbox(&outbox_rx, &writer));
+
+    let outbox_clone = outbox_tx.clone();
+    let timeout = Duration::from_secs(session.timeout_secs);
+    spawn_reporting(&error_tx, move || run_heartbeat(&outbox_clone, timeout));
+
+    // Opening the session may have taken a while, so the remote's silence only counts from here
+    let last_received = reader.last_received();
+    *helpers::lock(&last_received, "last received")? = Instant::now();
+    spawn_reporting(&error_tx, move || run_watchdog(&last_received, timeout));
+
     let base_dir_clone = base_dir.to_owned();
     let log_clone = log.clone();
     let sync_count_clone = sync_count.clone();
-    let child_2 = thread::spawn(move || {
+    spawn_reporting(&error_tx, move || {
         run_remote_listener(
             &log_clone,
             &base_dir_clone,
+            &session,
             reader,
+            &outbox_tx,
+            &replies_tx,
             &lock,
             &sync_count_clone,
-        );
+        )
     });
 
     let log_clone = log.clone();
     let (status_log_tx, status_log_rx) = mpsc::channel();
-    let child_3 = thread::spawn(move || {
-        run_status_logger(&log_clone, &sync_count, &status_log_rx);
+    spawn_reporting(&error_tx, move || {
+        run_status_logger(&log_clone, &sync_count, &status_log_rx)
     });
+    drop(error_tx);
 
     info!(log, "Ready!");
 
-    let _ = child_1.join();
-    let _ = child_2.join();
+    // Every thread runs for the whole session, so the first to stop ends it
+    let result = match error_rx.recv() {
+        Ok(e) => Err(e),
+        Err(_) => Ok(()),
+    };
     status_log_tx.send(()).unwrap_or_default();
-    let _ = child_3.join();
     info!(log, "BindRS Stopping");
+    result
 }
 
+/// Runs `task` on its own thread, passing on the error that stops it.
+fn spawn_reporting<F>(errors: &Sender<BindrsError>, task: F)
+where
+    F: FnOnce() -> Result<(), BindrsError> + Send + 'static,
+{
+    let errors = errors.clone();
+    thread::spawn(move || if let Err(e) = task() {
+        errors.send(e).unwrap_or_default();
+    });
+}
+
+#[cfg_attr(feature = "clippy", allow(too_many_arguments))]
 fn run_local_watcher<W: Write>(
     log: &Logger,
     base_dir: &str,
-    ignores: &RegexSet,
-    writer: W,
+    session: &Session,
+    initial_changes: Vec<(FileAction, String)>,
+    rx: &Receiver<(FileAction, String)>,
+    writer: &SharedWriter<W>,
     lock: &WatchLock,
     sync_count: &Arc<Mutex<(u32, u32)>>,
-) {
-    let mut writer = BufWriter::new(writer);
-    let mut watcher = BindrsWatcher::new(base_dir, ignores);
-    watcher.watch(log);
-    let rx = watcher.rx.unwrap_or_else(|| {
-        helpers::log_error_and_exit(log, "Couldn't get local receive channel off local watcher");
-        panic!();
-    });
+) -> Result<(), BindrsError> {
+    if !initial_changes.is_empty() {
+        info!(log, "Sending {} initial change/s to remote", initial_changes.len());
+        for (a, p) in initial_changes {
+            queue_change(log, base_dir, session, a, p, writer, lock, sync_count)?;
+        }
+        flush_writer(writer)?;
+    }
 
     loop {
-        let (a, p) = rx.recv().unwrap_or_else(|e| {
-            helpers::log_error_and_exit(
-                log,
-                &format!("Failed to receive message from local watcher: {}", e),
-            );
-            panic!(e)
-        });
-        let full_str_path = format!("{}/{}", base_dir, p);
-        let full_path = Path::new(&full_str_path);
+        let mut change = Some(rx.recv().map_err(|_| {
+            BindrsError::Watch("Local watcher stopped unexpectedly".to_owned())
+        })?);
 
-        if a == FileAction::CreateUpdate && full_path.is_dir() {
-            continue;
+        // Changes already waiting are sent together, but a lone change goes out straight away
+        let deadline = Instant::now() + Duration::from_millis(BATCH_TIME_BUDGET_MS);
+        while let Some((a, p)) = change {
+            queue_change(log, base_dir, session, a, p, writer, lock, sync_count)?;
+            change = if Instant::now() < deadline {
+                rx.try_recv().ok()
+            } else {
+                None
+            };
         }
+        flush_writer(writer)?;
+    }
+}
 
-        let p_clone = p.clone();
+#[cfg_attr(feature = "clippy", allow(too_many_arguments))]
+fn queue_change<W: Write>(
+    log: &Logger,
+    base_dir: &str,
+    session: &Session,
+    a: FileAction,
+    p: String,
+    writer: &SharedWriter<W>,
+    lock: &WatchLock,
+    sync_count: &Arc<Mutex<(u32, u32)>>,
+) -> Result<(), BindrsError> {
+    let full_str_path = format!("{}/{}", base_dir, p);
+    let full_path = Path::new(&full_str_path);
 
-        {
-            let mut recent_files = lock.lock().unwrap_or_else(|_| {
-                helpers::log_error_and_exit(log, "Failed to aquire local fs lock, lock poisoned");
-                panic!()
-            });
-            let (now_s, now_nano_s) = {
-                let now_spec = time::now().to_timespec();
-                (now_spec.sec, now_spec.nsec)
-            };
+    let a = if session.preserve_symlinks && a == FileAction::CreateUpdate && is_symlink(full_path) {
+        FileAction::Symlink
+    } else {
+        a
+    };
 
-            recent_files.retain(|&(_, ref time_s, ref time_nano_s)| if now_s - time_s > 1 {
-                false
-            } else if now_s - time_s == 1 {
-                now_nano_s - time_nano_s + 1_000_000_000 < 500_000_000
-            } else {
-                now_nano_s - time_nano_s < 500_000_000
-            });
+    if a == FileAction::CreateUpdate && full_path.is_dir() {
+        return Ok(());
+    }
 
-            let file_was_recently_synced = recent_files.iter().map(|&(ref path, _, _)| path).any(
-                |path| {
-                    &p_clone == path
-                },
-            );
+    let p_clone = p.clone();
+
+    {
+        let mut local = helpers::lock(lock, "local fs")?;
+        let local = &mut *local;
+        let recent_files = &mut local.recent_files;
+        let (now_s, now_nano_s) = {
+            let now_spec = time::now().to_timespec();
+            (now_spec.sec, now_spec.nsec)
+        };
+
+        recent_files.retain(|&(_, ref time_s, ref time_nano_s)| if now_s - time_s > 1 {
+            false
+        } else if now_s - time_s == 1 {
+            now_nano_s - time_nano_s + 1_000_000_000 < 500_000_000
+        } else {
+            now_nano_s - time_nano_s < 500_000_000
+        });
+
+        let file_was_recently_synced = recent_files.iter().map(|&(ref path, _, _)| path).any(
+            |path| {
+                &p_clone == path
+            },
+        );
 
-            if file_was_recently_synced {
-                continue;
+        if file_was_recently_synced {
+            if let Some(ref dirty) = local.dirty {
+                dirty.synced(&p_clone)?;
             }
+            return Ok(());
         }
+    }
 
-        let _guard = lock.lock().unwrap_or_else(|_| {
-            helpers::log_error_and_exit(log, "Failed to aquire local fs lock, lock poisoned");
-            panic!()
-        });
+    let mut local = helpers::lock(lock, "local fs")?;
 
-        if a == FileAction::CreateUpdate && !full_path.exists() {
-            debug!(log, "Skipping sending {} as file does not exist", p);
-        } else {
-            let bf = BoundFile::build_from_path_action(base_dir, p, a);
-            debug!(log, "Sending {} to remote", bf.path);
-            bf.to_writer(&mut writer);
-
-            {
-                let mut synced_nums = sync_count.lock().unwrap_or_else(|_| {
-                    helpers::log_error_and_exit(log, "Failed to aquire sync count lock, lock poisoned");
-                    panic!()
-                });
-                synced_nums.0 += 1;
+    if (a == FileAction::CreateUpdate || a == FileAction::Chmod || a == FileAction::CreateDir) &&
+        !full_path.exists()
+    {
+        debug!(log, "Skipping sending {} as file does not exist", p);
+        return Ok(());
+    }
+
+    let a = if session.preserve_hard_links {
+        match a {
+            FileAction::CreateUpdate => {
+                match local.hard_links.new_link_to(base_dir, &p) {
+                    Some(target) => FileAction::HardLink { target },
+                    None => a,
+                }
+            }
+            _ => {
+                local.hard_links.applied(base_dir, &a, &p);
+                a
             }
         }
+    } else {
+        a
+    };
+
+    // The remote only renames its old copy, so edits still waiting on signatures are sent again
+    // from where they ended up
+    let moved: Vec<String> = match a {
+        FileAction::Rename { ref from } => {
+            local.awaiting_signatures.iter().filter_map(|pending| moved_path(from, &p, pending)).collect()
+        }
+        _ => vec![],
+    };
+
+    let requests_delta = a == FileAction::CreateUpdate && session.delta && is_delta_candidate(full_path);
+    if requests_delta {
+        local.awaiting_signatures.insert(p.clone());
+    }
+    // Sending can wait on the remote, which may be waiting on the listener, which needs this lock
+    drop(local);
+
+    let sent = if requests_delta {
+        debug!(log, "Requesting signatures for {} from remote", p);
+        write_frame(writer, &Frame::SignatureRequest(p))
+    } else {
+        skip_on_file_error(log, &p_clone, || {
+            let sends_contents = a == FileAction::CreateUpdate;
+            let bf = BoundFile::build_from_path_action(base_dir, p, a, session)?;
+            debug!(log, "Sending {} to remote", bf.path);
+            send_file(base_dir, bf, None, writer, lock, 0)?;
+            increment_sync_count(sync_count, true)?;
+            if sends_contents {
+                send_links(log, base_dir, session, &p_clone, writer, lock)?;
+            }
+            Ok(())
+        })
+    };
+
+    for path in moved {
+        queue_change(log, base_dir, session, FileAction::CreateUpdate, path, writer, lock, sync_count)?;
     }
+    sent
 }
 
+/// Where `pending` went when `from` was renamed to `to`, if it was `from` or inside it.
+fn moved_path(from: &str, to: &str, pending: &str) -> Option<String> {
+    if pending == from {
+        Some(to.to_owned())
+    } else if pending.starts_with(from) && pending[from.len()..].starts_with('/') {
+        Some(format!("{}{}", to, &pending[from.len()..]))
+    } else {
+        None
+    }
+}
+
+/// New contents replace the file rather than writing through every link to it, so the remote's
+/// other links are pointed back at it.
+fn send_links<W: Write>(
+    log: &Logger,
+    base_dir: &str,
+    session: &Session,
+    path: &str,
+    writer: &SharedWriter<W>,
+    lock: &WatchLock,
+) -> Result<(), BindrsError> {
+    if !session.preserve_hard_links {
+        return Ok(());
+    }
+
+    let others = helpers::lock(lock, "local fs")?.hard_links.others(base_dir, path);
+    for other in others {
+        let action = FileAction::HardLink { target: path.to_owned() };
+        let bf = BoundFile::build_from_path_action(base_dir, other, action, session)?;
+        debug!(log, "Linking {} to {} on remote", bf.path, path);
+        send_file(base_dir, bf, None, writer, lock, 0)?;
+    }
+    Ok(())
+}
+
+/// Applies what the remote sends. Anything it has to send in return is handed to another thread,
+/// as waiting on a send here could leave both sides writing with neither reading.
+#[cfg_attr(feature = "clippy", allow(too_many_arguments))]
 fn run_remote_listener<R: Read>(
     log: &Logger,
     base_dir: &str,
-    reader: R,
+    session: &Session,
+    mut reader: FrameReader<R>,
+    outbox: &Sender<Vec<Frame>>,
+    replies: &Sender<Reply>,
     lock: &WatchLock,
     sync_count: &Arc<Mutex<(u32, u32)>>,
-) {
-    let mut reader = BufReader::new(reader);
+) -> Result<(), BindrsError> {
+    let mut outgoing = vec![];
     loop {
-        let bf = BoundFile::from_reader(&mut reader);
-        let mut recent_files = lock.lock().unwrap_or_else(|_| {
-            helpers::log_error_and_exit(log, "Failed to aquire local fs lock, lock poisoned");
-            panic!()
-        });
-        debug!(log, "Receiving {} from remote", bf.path);
-        bf.save_to_disk(base_dir);
+        match reader.recv()? {
+            (seq, Frame::File(mut bf)) => {
+                bf.owner = bf.owner.take().map(|owner| session.owner_map.to_local(owner));
+                // Failing to apply a file is the remote's to retry, anything else ends the session
+                let result = match receive_file(log, base_dir, session, bf, &mut reader, lock, sync_count) {
+                    Ok(()) => Ok(()),
+                    Err(BindrsError::File(e)) |
+                    Err(BindrsError::Path(e)) => Err(e),
+                    Err(e) => return Err(e),
+                };
+                outgoing.push(Frame::Ack(seq, result));
+            }
+            (_, Frame::SignatureRequest(path)) => {
+                // Without signatures the remote sends the whole file, which is then refused too
+                let signatures = match check_path(base_dir, &path) {
+                    Ok(()) => {
+                        debug!(log, "Sending signatures for {} to remote", path);
+                        BoundFile::signatures_from_disk(base_dir, &path)
+                    }
+                    Err(e) => {
+                        error!(log, "{}", e);
+                        None
+                    }
+                };
+                outgoing.push(Frame::Signatures(path, signatures));
+            }
+            (_, Frame::Signatures(path, signatures)) => {
+                match check_path(base_dir, &path) {
+                    Ok(()) => reply(replies, Reply::Delta(path, signatures))?,
+                    Err(e) => error!(log, "{}", e),
+                }
+            }
+            (_, Frame::Ack(seq, result)) => receive_ack(log, seq, result, replies, lock)?,
+            (_, Frame::Heartbeat) => (),
+            (_, Frame::Chunk(_)) |
+            (_, Frame::End(_)) => {
+                return Err(BindrsError::Protocol(
+                    "Received file contents without a file header".to_owned(),
+                ));
+            }
+            (_, Frame::Handshake(_)) |
+            (_, Frame::Manifest(..)) |
+            (_, Frame::Pull(..)) => {
+                return Err(BindrsError::Protocol(
+                    "Received handshake after session started".to_owned(),
+                ));
+            }
+        }
 
-        let (now_s, now_nano_s) = {
-            let now_spec = time::now().to_timespec();
-            (now_spec.sec, now_spec.nsec)
+        // Replies to a batch go back as a batch
+        if !reader.has_buffered() && !outgoing.is_empty() {
+            outbox.send(mem::replace(&mut outgoing, vec![])).map_err(|_| {
+                BindrsError::Internal("Remote writer stopped unexpectedly".to_owned())
+            })?;
+        }
+    }
+}
+
+fn receive_file<R: Read>(
+    log: &Logger,
+    base_dir: &str,
+    session: &Session,
+    bf: BoundFile,
+    reader: &mut FrameReader<R>,
+    lock: &WatchLock,
+    sync_count: &Arc<Mutex<(u32, u32)>>,
+) -> Result<(), BindrsError> {
+    debug!(log, "Receiving {} from remote", bf.path);
+    // Not locked while the contents arrive, as the remote may only send the rest once this side's
+    // own sends get through, and those need the lock too. Noting the file first still keeps the
+    // watcher from sending it straight back.
+    note_recent(lock, &bf.path)?;
+    if let Err(e) = bf.save_to_disk(log, base_dir, reader) {
+        error!(log, "{}", e);
+        return Err(e);
+    }
+    if session.preserve_hard_links {
+        helpers::lock(lock, "local fs")?.hard_links.applied(base_dir, &bf.action, &bf.path);
+    }
+
+    note_recent(lock, &bf.path)?;
+    increment_sync_count(sync_count, false)
+}
+
+fn note_recent(lock: &WatchLock, path: &str) -> Result<(), BindrsError> {
+    let (now_s, now_nano_s) = {
+        let now_spec = time::now().to_timespec();
+        (now_spec.sec, now_spec.nsec)
+    };
+    helpers::lock(lock, "local fs")?.recent_files.push((path.to_owned(), now_s, now_nano_s));
+    Ok(())
+}
+
+fn receive_ack(
+    log: &Logger,
+    seq: u64,
+    result: Result<(), String>,
+    replies: &Sender<Reply>,
+    lock: &WatchLock,
+) -> Result<(), BindrsError> {
+    let change = match helpers::lock(lock, "local fs")?.unconfirmed.remove(&seq) {
+        Some(change) => change,
+        None => {
+            warn!(log, "Remote acknowledged unknown change {}", seq);
+            return Ok(());
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            debug!(log, "Remote applied {}", change.path);
+            if let Some(ref dirty) = helpers::lock(lock, "local fs")?.dirty {
+                dirty.synced(&change.path)?;
+                if let FileAction::Rename { ref from } = change.action {
+                    dirty.synced(from)?;
+                }
+            }
+        }
+        Err(ref e) if change.attempts >= MAX_RETRIES => {
+            error!(
+                log,
+                "Remote failed to apply {}: {}. Giving up after {} retries",
+                change.path,
+                e,
+                MAX_RETRIES
+            );
+        }
+        Err(e) => {
+            warn!(log, "Remote failed to apply {}: {}. Retrying", change.path, e);
+            reply(replies, Reply::Retry(change))?;
+        }
+    }
+    Ok(())
+}
+
+fn reply(replies: &Sender<Reply>, reply: Reply) -> Result<(), BindrsError> {
+    replies.send(reply).map_err(|_| {
+        BindrsError::Internal("Reply sender stopped unexpectedly".to_owned())
+    })
+}
+
+#[cfg_attr(feature = "clippy", allow(too_many_arguments))]
+fn run_replies<W: Write>(
+    log: &Logger,
+    base_dir: &str,
+    ignores: &RegexSet,
+    session: &Session,
+    replies: &Receiver<Reply>,
+    writer: &SharedWriter<W>,
+    lock: &WatchLock,
+    sync_count: &Arc<Mutex<(u32, u32)>>,
+) -> Result<(), BindrsError> {
+    // Ends once the listener does
+    while let Ok(first) = replies.recv() {
+        let mut next = Some(first);
+        while let Some(reply) = next {
+            match reply {
+                Reply::Delta(path, signatures) => {
+                    send_delta(log, base_dir, session, path, signatures.as_ref(), writer, lock, sync_count)?
+                }
+                Reply::Retry(change) => retry_change(log, base_dir, ignores, session, change, writer, lock, sync_count)?,
+            }
+            next = replies.try_recv().ok();
+        }
+        flush_writer(writer)?;
+    }
+    Ok(())
+}
+
+#[cfg_attr(feature = "clippy", allow(too_many_arguments))]
+fn retry_change<W: Write>(
+    log: &Logger,
+    base_dir: &str,
+    ignores: &RegexSet,
+    session: &Session,
+    change: Unconfirmed,
+    writer: &SharedWriter<W>,
+    lock: &WatchLock,
+    sync_count: &Arc<Mutex<(u32, u32)>>,
+) -> Result<(), BindrsError> {
+    // A link the remote couldn't make is sent as a copy instead, and a rename as whatever is now
+    // at its destination
+    let (changes, sends_links) = match change.action {
+        FileAction::HardLink { .. } => (vec![(FileAction::CreateUpdate, change.path)], false),
+        FileAction::CreateUpdate => (vec![(FileAction::CreateUpdate, change.path)], true),
+        FileAction::Rename { .. } => (recreate(base_dir, ignores, session, change.path), false),
+        action => (vec![(action, change.path)], false),
+    };
+
+    let attempts = change.attempts + 1;
+    for (action, path) in changes {
+        let full_str_path = format!("{}/{}", base_dir, path);
+        let still_exists = match action {
+            FileAction::CreateUpdate => Path::new(&full_str_path).is_file(),
+            FileAction::Chmod |
+            FileAction::Symlink |
+            FileAction::CreateDir => fs::symlink_metadata(&full_str_path).is_ok(),
+            _ => true,
         };
-        recent_files.push((bf.path, now_s, now_nano_s));
+        if !still_exists {
+            debug!(log, "Skipping retry of {} as file does not exist", path);
+            continue;
+        }
 
-        {
-            let mut synced_nums = sync_count.lock().unwrap_or_else(|_| {
-                helpers::log_error_and_exit(log, "Failed to aquire sync count lock, lock poisoned");
-                panic!()
-            });
-            synced_nums.1 += 1;
+        // Contents are resent in full, in case the remote's copy is what went wrong
+        skip_on_file_error(log, &path, || {
+            let bf = BoundFile::build_from_path_action(base_dir, path.clone(), action, session)?;
+            send_file(base_dir, bf, None, writer, lock, attempts)?;
+            increment_sync_count(sync_count, true)?;
+            if sends_links {
+                send_links(log, base_dir, session, &path, writer, lock)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// The changes that send `path`, and everything in it if it's a directory, in full.
+fn recreate(base_dir: &str, ignores: &RegexSet, session: &Session, path: String) -> Vec<(FileAction, String)> {
+    let sent_as = |action: FileAction, path: &str| if session.preserve_symlinks &&
+        is_symlink(Path::new(&format!("{}/{}", base_dir, path)))
+    {
+        FileAction::Symlink
+    } else {
+        action
+    };
+
+    let action = if Path::new(&format!("{}/{}", base_dir, path)).is_dir() {
+        sent_as(FileAction::CreateDir, &path)
+    } else {
+        sent_as(FileAction::CreateUpdate, &path)
+    };
+    let mut changes = vec![(action.clone(), path.clone())];
+    if action == FileAction::CreateDir {
+        walk_children(base_dir, &path, ignores, &mut |action, child| {
+            changes.push((sent_as(action, &child), child));
+        });
+    }
+    changes
+}
+
+#[cfg_attr(feature = "clippy", allow(too_many_arguments))]
+fn send_delta<W: Write>(
+    log: &Logger,
+    base_dir: &str,
+    session: &Session,
+    path: String,
+    signatures: Option<&Signatures>,
+    writer: &SharedWriter<W>,
+    lock: &WatchLock,
+    sync_count: &Arc<Mutex<(u32, u32)>>,
+) -> Result<(), BindrsError> {
+    helpers::lock(lock, "local fs")?.awaiting_signatures.remove(&path);
+
+    if !Path::new(&format!("{}/{}", base_dir, path)).is_file() {
+        debug!(log, "Skipping sending {} as file does not exist", path);
+        return Ok(());
+    }
+
+    let path_clone = path.clone();
+    skip_on_file_error(log, &path_clone, || {
+        let bf = BoundFile::build_from_path_action(base_dir, path, FileAction::CreateUpdate, session)?;
+        match signatures {
+            Some(_) => debug!(log, "Sending delta of {} to remote", bf.path),
+            None => debug!(log, "Sending {} to remote", bf.path),
+        }
+        send_file(base_dir, bf, signatures, writer, lock, 0)?;
+        increment_sync_count(sync_count, true)?;
+        send_links(log, base_dir, session, &path_clone, writer, lock)
+    })
+}
+
+/// A local file changing or vanishing while it's read shouldn't end the session, so those errors
+/// are logged and the change skipped.
+fn skip_on_file_error<F: FnOnce() -> Result<(), BindrsError>>(log: &Logger, path: &str, send: F) -> Result<(), BindrsError> {
+    match send() {
+        Err(BindrsError::File(e)) => {
+            warn!(log, "Skipping sending {}: {}", path, e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn send_file<W: Write>(
+    base_dir: &str,
+    bf: BoundFile,
+    signatures: Option<&Signatures>,
+    writer: &SharedWriter<W>,
+    lock: &WatchLock,
+    attempts: u32,
+) -> Result<(), BindrsError> {
+    let change = Unconfirmed {
+        path: bf.path.clone(),
+        action: bf.action.clone(),
+        attempts,
+    };
+    let mut frames = helpers::lock(writer, "remote writer")?;
+    // Queued before the header goes out so the ack can't arrive first
+    let seq = frames.next_seq();
+    helpers::lock(lock, "local fs")?.unconfirmed.insert(seq, change);
+
+    let sent = bf.to_writer(base_dir, &mut frames, signatures);
+    if sent.is_err() && frames.next_seq() == seq {
+        // Nothing went out, so no ack is coming
+        helpers::lock(lock, "local fs")?.unconfirmed.remove(&seq);
+    }
+    sent.map(|_| ())
+}
+
+fn write_frame<W: Write>(writer: &SharedWriter<W>, frame: &Frame) -> Result<(), BindrsError> {
+    helpers::lock(writer, "remote writer")?.send(frame).map(|_| ())
+}
+
+fn flush_writer<W: Write>(writer: &SharedWriter<W>) -> Result<(), BindrsError> {
+    helpers::lock(writer, "remote writer")?.flush()
+}
+
+/// Writes the frames the listener and heartbeat queue, waiting out any file being sent.
+fn run_outbox<W: Write>(outbox: &Receiver<Vec<Frame>>, writer: &SharedWriter<W>) -> Result<(), BindrsError> {
+    // Ends once the listener and heartbeat do
+    while let Ok(mut frames) = outbox.recv() {
+        let mut remote = helpers::lock(writer, "remote writer")?;
+        // Everything queued while waiting for the writer goes out together
+        while let Ok(more) = outbox.try_recv() {
+            frames.extend(more);
+        }
+        // Any other frame shows the remote we're alive just as well
+        frames.retain(|frame| *frame != Frame::Heartbeat);
+        if frames.is_empty() {
+            frames.push(Frame::Heartbeat);
+        }
+
+        for frame in &frames {
+            remote.send(frame)?;
         }
+        remote.flush()?;
+    }
+    Ok(())
+}
+
+fn increment_sync_count(sync_count: &Arc<Mutex<(u32, u32)>>, sent: bool) -> Result<(), BindrsError> {
+    let mut synced_nums = helpers::lock(sync_count, "sync count")?;
+    if sent {
+        synced_nums.0 += 1;
+    } else {
+        synced_nums.1 += 1;
     }
+    Ok(())
 }
 
-fn run_status_logger(log: &Logger, sync_count: &Arc<Mutex<(u32, u32)>>, rx: &Receiver<()>) {
+// Sparse files are sent as their data and holes instead, as diffing would read every hole
+fn is_delta_candidate(full_path: &Path) -> bool {
+    match full_path.metadata() {
+        Ok(metadata) => metadata.is_file() && metadata.len() >= DELTA_MIN_SIZE && !sparse::is_sparse(&metadata),
+        Err(_) => false,
+    }
+}
+
+fn run_heartbeat(outbox: &Sender<Vec<Frame>>, timeout: Duration) -> Result<(), BindrsError> {
+    // Several beats per timeout so a single late one doesn't look like a dead connection
+    let interval = timeout / 3;
+    loop {
+        sleep(interval);
+        if outbox.send(vec![Frame::Heartbeat]).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn run_watchdog(last_received: &Arc<Mutex<Instant>>, timeout: Duration) -> Result<(), BindrsError> {
+    loop {
+        sleep(Duration::from_secs(1));
+        let silent_for = helpers::lock(last_received, "last received")?.elapsed();
+
+        if silent_for > timeout {
+            return Err(BindrsError::Disconnected(format!(
+                "Heard nothing from remote for {} seconds, the connection appears to be dead",
+                silent_for.as_secs()
+            )));
+        }
+    }
+}
+
+fn run_status_logger(log: &Logger, sync_count: &Arc<Mutex<(u32, u32)>>, rx: &Receiver<()>) -> Result<(), BindrsError> {
     loop {
         sleep(Duration::from_millis(1000));
         match rx.try_recv() {
             Ok(_) |
-            Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Disconnected) => return Ok(()),
             Err(TryRecvError::Empty) => (),
         }
 
         {
-            let mut synced_nums = sync_count.lock().unwrap_or_else(|_| {
-                helpers::log_error_and_exit(log, "Failed to aquire sync count lock, lock poisoned");
-                panic!()
-            });
+            let mut synced_nums = helpers::lock(sync_count, "sync count")?;
 
             let to_log = synced_nums.0 > 0 || synced_nums.1 > 0;
             let mut message: Vec<String> = vec![];
@@ -219,3 +815,163 @@ fn run_status_logger(log: &Logger, sync_count: &Arc<Mutex<(u32, u32)>>, rx: &Rec
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::Discard;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc::channel;
+    use structs::handshake::Handshake;
+    use test_support::{eventually, test_dir};
+
+    /// Syncs `local_dir` with `remote_dir` in the background, each side taking its local changes
+    /// from the returned senders rather than a watcher.
+    fn start_pair(local_dir: &str, remote_dir: &str) -> (Sender<(FileAction, String)>, Sender<(FileAction, String)>) {
+        // A socket rather than an in-memory pipe, so sends block once the remote stops reading
+        let (local, remote) = UnixStream::pair().unwrap();
+        (start_side(local_dir, local), start_side(remote_dir, remote))
+    }
+
+    fn start_side(dir: &str, stream: UnixStream) -> Sender<(FileAction, String)> {
+        let (tx, rx) = channel();
+        let dir = dir.to_owned();
+        let reader = stream.try_clone().unwrap();
+        thread::spawn(move || {
+            let ignores = helpers::process_ignores(&mut vec![]).unwrap();
+            start(
+                &Logger::root(Discard, o!()),
+                &dir,
+                &ignores,
+                FrameReader::new(reader),
+                FrameWriter::new(stream),
+                Session::from_handshake(&Handshake::local()),
+                vec![],
+                rx,
+                None,
+            )
+        });
+        tx
+    }
+
+    #[test]
+    fn failed_acks_are_retried_up_to_the_limit() {
+        let log = Logger::root(Discard, o!());
+        let lock: WatchLock = Arc::new(Mutex::new(LocalFs {
+            recent_files: vec![],
+            hard_links: HardLinks::default(),
+            dirty: None,
+            awaiting_signatures: HashSet::new(),
+            unconfirmed: BTreeMap::new(),
+        }));
+        let unconfirmed = |attempts| Unconfirmed {
+            path: "f".to_owned(),
+            action: FileAction::CreateUpdate,
+            attempts,
+        };
+        {
+            let mut local = lock.lock().unwrap();
+            local.unconfirmed.insert(1, unconfirmed(0));
+            local.unconfirmed.insert(2, unconfirmed(MAX_RETRIES));
+            local.unconfirmed.insert(3, unconfirmed(0));
+        }
+        let (replies, rx) = channel();
+
+        receive_ack(&log, 1, Err("hash mismatch".to_owned()), &replies, &lock).unwrap();
+        match rx.try_recv() {
+            Ok(Reply::Retry(ref change)) if change.path == "f" => (),
+            _ => panic!("expected a failed change to be retried"),
+        }
+        receive_ack(&log, 2, Err("hash mismatch".to_owned()), &replies, &lock).unwrap();
+        receive_ack(&log, 3, Ok(()), &replies, &lock).unwrap();
+        assert!(rx.try_recv().is_err());
+        assert!(lock.lock().unwrap().unconfirmed.is_empty());
+    }
+
+    #[test]
+    fn silent_remotes_are_a_disconnect() {
+        let last_received = Arc::new(Mutex::new(Instant::now()));
+        let timeout = Duration::from_secs(1);
+
+        match run_watchdog(&last_received, timeout) {
+            Err(BindrsError::Disconnected(_)) => (),
+            _ => panic!("expected the watchdog to give up on a silent remote"),
+        }
+        assert!(last_received.lock().unwrap().elapsed() > timeout);
+    }
+
+    #[test]
+    fn edits_waiting_on_signatures_follow_a_rename() {
+        let (_local, local_dir) = test_dir("bindrs-executor-local");
+        let (_remote, remote_dir) = test_dir("bindrs-executor-remote");
+        let old: Vec<u8> = (0..DELTA_MIN_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[10] = 0;
+        fs::write(format!("{}/a", remote_dir), &old).unwrap();
+        // `a` is left in place locally so the outcome doesn't depend on when signatures arrive
+        fs::write(format!("{}/a", local_dir), &new).unwrap();
+        fs::write(format!("{}/b", local_dir), &new).unwrap();
+
+        let (changes, _remote_changes) = start_pair(&local_dir, &remote_dir);
+        changes.send((FileAction::CreateUpdate, "a".to_owned())).unwrap();
+        changes.send((FileAction::Rename { from: "a".to_owned() }, "b".to_owned())).unwrap();
+
+        let remote_b = format!("{}/b", remote_dir);
+        assert!(eventually(|| fs::read(&remote_b).ok() == Some(new.clone())));
+    }
+
+    #[test]
+    fn renames_the_remote_cant_apply_are_sent_in_full() {
+        let (_local, local_dir) = test_dir("bindrs-executor-local");
+        let (_remote, remote_dir) = test_dir("bindrs-executor-remote");
+        fs::write(format!("{}/b", local_dir), b"moved file").unwrap();
+        fs::create_dir(format!("{}/d", local_dir)).unwrap();
+        fs::write(format!("{}/d/x", local_dir), b"inside moved dir").unwrap();
+
+        let (changes, _remote_changes) = start_pair(&local_dir, &remote_dir);
+        changes.send((FileAction::Rename { from: "a".to_owned() }, "b".to_owned())).unwrap();
+        changes.send((FileAction::Rename { from: "c".to_owned() }, "d".to_owned())).unwrap();
+
+        let (remote_b, remote_x) = (format!("{}/b", remote_dir), format!("{}/d/x", remote_dir));
+        assert!(eventually(|| fs::read(&remote_b).ok() == Some(b"moved file".to_vec())));
+        assert!(eventually(|| fs::read(&remote_x).ok() == Some(b"inside moved dir".to_vec())));
+    }
+
+    #[test]
+    fn other_links_are_relinked_after_an_update() {
+        let (_local, local_dir) = test_dir("bindrs-executor-local");
+        let (_remote, remote_dir) = test_dir("bindrs-executor-remote");
+        for dir in &[&local_dir, &remote_dir] {
+            fs::write(format!("{}/a", dir), b"old contents").unwrap();
+            fs::hard_link(format!("{}/a", dir), format!("{}/b", dir)).unwrap();
+        }
+
+        let (changes, _remote_changes) = start_pair(&local_dir, &remote_dir);
+        fs::write(format!("{}/a", local_dir), b"new contents").unwrap();
+        changes.send((FileAction::CreateUpdate, "a".to_owned())).unwrap();
+
+        // The new `a` replaces the remote's old one, so `b` has to be pointed back at it
+        let (remote_a, remote_b) = (format!("{}/a", remote_dir), format!("{}/b", remote_dir));
+        let inode = |path: &str| fs::metadata(path).map(|metadata| metadata.ino()).ok();
+        assert!(eventually(|| fs::read(&remote_b).ok() == Some(b"new contents".to_vec())));
+        assert_eq!(inode(&remote_a), inode(&remote_b));
+    }
+
+    #[test]
+    fn both_sides_can_send_large_files_at_once() {
+        let (_local, local_dir) = test_dir("bindrs-executor-local");
+        let (_remote, remote_dir) = test_dir("bindrs-executor-remote");
+        let contents: Vec<u8> = (0..2 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(format!("{}/up", local_dir), &contents).unwrap();
+        fs::write(format!("{}/down", remote_dir), &contents).unwrap();
+
+        let (changes, remote_changes) = start_pair(&local_dir, &remote_dir);
+        changes.send((FileAction::CreateUpdate, "up".to_owned())).unwrap();
+        remote_changes.send((FileAction::CreateUpdate, "down".to_owned())).unwrap();
+
+        let (remote_up, local_down) = (format!("{}/up", remote_dir), format!("{}/down", local_dir));
+        assert!(eventually(|| fs::read(&remote_up).ok() == Some(contents.clone())));
+        assert!(eventually(|| fs::read(&local_down).ok() == Some(contents.clone())));
+    }
+}
@@ -1,57 +1,64 @@
-use helpers;
+use error::BindrsError;
 use regex::RegexSet;
 use slog::Logger;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::path::Path;
 use std::process::Command;
+use structs::handshake::Session;
 use structs::remote_info::RemoteInfo;
 use tempdir::TempDir;
 
-pub fn run(log: &Logger, base_dir: &str, remote_info: &RemoteInfo, ignores: &RegexSet) {
-    let temp_dir = create_temp_dir(log, "rsync-data");
+pub fn run(
+    log: &Logger,
+    base_dir: &str,
+    remote_info: &RemoteInfo,
+    ignores: &RegexSet,
+    session: &Session,
+) -> Result<(), BindrsError> {
+    let temp_dir = create_temp_dir("rsync-data")?;
     let ignore_file_pathbuf = temp_dir.path().join("rsync-ignores");
     let ignore_file_path = ignore_file_pathbuf.as_path();
     let ignore_file_string_path = ignore_file_path.to_string_lossy().into_owned();
 
-    build_rsync_ignore_file(log, ignore_file_path, base_dir, remote_info, ignores);
-    let args_vec = rsync_args(base_dir, remote_info, &ignore_file_string_path);
+    build_rsync_ignore_file(ignore_file_path, base_dir, remote_info, ignores)?;
+    let args_vec = rsync_args(
+        base_dir,
+        remote_info,
+        &ignore_file_string_path,
+        session,
+    );
 
     info!(log, "Running initial rsync");
-    match Command::new("rsync").args(&args_vec).output() {
-        Ok(output) => {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            if stdout != "" {
-                debug!(log, "{}", stdout);
-            }
-            if stderr != "" {
-                helpers::log_error_and_exit(log, &stderr);
-            }
-            debug!(log, "Finished initial rsync");
-        }
-        Err(e) => helpers::log_error_and_exit(log, &format!("Failed to run rsync: {}", e)),
+    let output = Command::new("rsync").args(&args_vec).output().map_err(|e| {
+        BindrsError::Rsync(format!("Failed to run rsync: {}", e))
+    })?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stdout != "" {
+        debug!(log, "{}", stdout);
+    }
+    if stderr != "" {
+        return Err(BindrsError::Rsync(stderr.into_owned()));
     }
+    debug!(log, "Finished initial rsync");
+    Ok(())
 }
 
 fn build_rsync_ignore_file(
-    log: &Logger,
     ignore_file_path: &Path,
     base_dir: &str,
     remote_info: &RemoteInfo,
     ignores: &RegexSet,
-) {
+) -> Result<(), BindrsError> {
     let mut ignore_file = OpenOptions::new()
         .create(true)
         .write(true)
         .truncate(true)
         .open(ignore_file_path)
-        .unwrap_or_else(|e| {
-            helpers::log_error_and_exit(log, &format!("Could not create temp file: {}", e));
-            panic!(e);
-        });
+        .map_err(|e| BindrsError::Rsync(format!("Could not create temp file: {}", e)))?;
 
-    let mut folders = find_rsync_ignore_folders(log, base_dir, remote_info, ignores);
+    let mut folders = find_rsync_ignore_folders(base_dir, remote_info, ignores)?;
     folders.sort_by(|a, b| a.len().cmp(&b.len()));
 
     let mut written_folders: Vec<String> = vec![];
@@ -66,24 +73,53 @@ fn build_rsync_ignore_file(
         }
 
         written_folders.push(path.clone());
-        if let Err(e) = writeln!(ignore_file, "{}", path) {
-            helpers::log_error_and_exit(
-                log,
-                &format!("Could not append rsync ignore to temp file: {}", e),
-            )
-        }
+        writeln!(ignore_file, "{}", path).map_err(|e| {
+            BindrsError::Rsync(format!("Could not append rsync ignore to temp file: {}", e))
+        })?;
     }
+    Ok(())
 }
 
-fn rsync_args(base_dir: &str, remote_info: &RemoteInfo, ignore_file_path: &str) -> Vec<String> {
+fn rsync_args(
+    base_dir: &str,
+    remote_info: &RemoteInfo,
+    ignore_file_path: &str,
+    session: &Session,
+) -> Vec<String> {
     let mut args_vec: Vec<String> = vec!["-azv".to_owned()];
 
+    if !session.preserve_symlinks {
+        args_vec.push("--copy-links".to_owned());
+    }
+
+    if session.preserve_atimes {
+        args_vec.push("--atimes".to_owned());
+    }
+
+    if !session.xattr_namespaces.is_empty() {
+        args_vec.push("--xattrs".to_owned());
+        if session.xattr_namespaces.names().contains(&"system") {
+            args_vec.push("--acls".to_owned());
+        }
+    }
+
+    if session.preserve_owners {
+        args_vec.extend(session.owner_map.rsync_args());
+    }
+
+    if session.preserve_hard_links {
+        args_vec.push("--hard-links".to_owned());
+    }
+
+    // Holes are kept rather than filled in with zeros on the remote
+    args_vec.push("--sparse".to_owned());
+
     args_vec.push("--exclude-from".to_owned());
     args_vec.push(ignore_file_path.to_owned());
 
     if remote_info.is_remote {
         args_vec.push("-e".to_owned());
-        args_vec.push(format!("ssh -p {}", remote_info.port));
+        args_vec.push(remote_info.rsync_shell());
     }
 
     args_vec.push("--delete".to_owned());
@@ -94,48 +130,41 @@ fn rsync_args(base_dir: &str, remote_info: &RemoteInfo, ignore_file_path: &str)
 }
 
 fn find_rsync_ignore_folders(
-    log: &Logger,
     base_dir: &str,
     remote_info: &RemoteInfo,
     ignores: &RegexSet,
-) -> Vec<String> {
-    let mut folders = match Command::new("find")
+) -> Result<Vec<String>, BindrsError> {
+    let local = Command::new("find")
         .arg(base_dir)
         .arg("-type")
         .arg("d")
-        .output() {
-        Ok(o) => process_raw_file_list(base_dir, String::from_utf8_lossy(&o.stdout).to_mut()),
-        Err(e) => {
-            helpers::log_error_and_exit(log, &format!("Failed to run local find: {}", e));
-            vec![]
-        }
-    };
+        .output()
+        .map_err(|e| BindrsError::Rsync(format!("Failed to run local find: {}", e)))?;
+    let mut folders = process_raw_file_list(base_dir, String::from_utf8_lossy(&local.stdout).to_mut());
 
     let cmd = &format!("find {} -type d", remote_info.path);
-    match remote_info
+    let remote = remote_info
         .generate_command(&mut remote_info.base_command(cmd), cmd)
-        .output() {
-        Ok(o) => {
-            folders.append(&mut process_raw_file_list(
-                &remote_info.path,
-                String::from_utf8_lossy(&o.stdout).to_mut(),
-            ))
-        }
-        Err(e) => helpers::log_error_and_exit(log, &format!("Failed to run remote find: {}", e)),
-    }
+        .output()
+        .map_err(|e| BindrsError::Remote(format!("Failed to run remote find: {}", e)))?;
+    folders.append(&mut process_raw_file_list(
+        &remote_info.path,
+        String::from_utf8_lossy(&remote.stdout).to_mut(),
+    ));
 
     folders.sort();
     folders.dedup();
-    folders
-        .into_iter()
-        .filter(|f| ignores.is_match(f))
-        .collect()
+    Ok(
+        folders
+            .into_iter()
+            .filter(|f| ignores.is_match(f))
+            .collect(),
+    )
 }
 
-fn create_temp_dir(log: &Logger, name: &str) -> TempDir {
-    TempDir::new(name).unwrap_or_else(|e| {
-        helpers::log_error_and_exit(log, &format!("Could not create temp directory: {}", e));
-        panic!(e);
+fn create_temp_dir(name: &str) -> Result<TempDir, BindrsError> {
+    TempDir::new(name).map_err(|e| {
+        BindrsError::Rsync(format!("Could not create temp directory: {}", e))
     })
 }
 
@@ -1,11 +1,213 @@
+use error::BindrsError;
 use helpers;
 use processors::executor;
+use regex::RegexSet;
 use slog::Logger;
-use std::io::{self, BufReader, BufWriter};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use structs::bound_file::check_path;
+use structs::connection::{self, Connection};
+use structs::frame::{Frame, FrameReader, FrameWriter};
+use structs::handshake::{Handshake, Session};
+use structs::manifest::{self, Manifest};
+use structs::tcp;
+use structs::unix_socket;
+use structs::watcher::BindrsWatcher;
 
-pub fn run(log: &Logger, base_dir: &str, ignore_strings: &mut Vec<String>) {
-    let ignores = helpers::process_ignores(log, ignore_strings);
+pub fn run(log: &Logger, base_dir: &str, ignore_strings: &mut Vec<String>) -> Result<(), BindrsError> {
+    let ignores = helpers::process_ignores(ignore_strings)?;
+    serve(log, base_dir, ignores, io::stdin(), io::stdout())
+}
+
+/// Waits for masters to connect on `address`, serving one at a time.
+pub fn listen(log: &Logger, base_dir: &str, address: &str, key: &[u8], ignore_strings: &[String]) -> Result<(), BindrsError> {
+    let listener = TcpListener::bind(address).map_err(|e| {
+        BindrsError::Config(format!("Failed to listen on {}: {}", address, e))
+    })?;
+    info!(log, "Listening on {}", address);
+
+    serve_each(log, listener.incoming(), |stream: TcpStream| {
+        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_else(|_| "unknown".to_owned());
+        info!(log, "Connection from {}", peer);
+
+        let served = serve_connection(log, stream, |stream| {
+            let ((dir, ignores), reader, writer) = tcp::accept(stream, key, |request| {
+                let dir = requested_dir(base_dir, &request.path)?;
+                let mut ignores = ignore_strings.to_vec();
+                ignores.extend(request.ignores);
+                Ok((dir, helpers::process_ignores(&mut ignores)?))
+            })?;
+            info!(log, "Syncing {}", dir);
+            Ok((dir, ignores, reader, writer))
+        });
+        match served {
+            Ok(()) => info!(log, "Session with {} ended", peer),
+            Err(e) => warn!(log, "Session with {} ended: {}", peer, e),
+        }
+    });
+    Ok(())
+}
+
+/// Serves masters that attach to the socket at `socket_path`, one at a time. The slave outlives
+/// each master, so a restarted master picks up where the last one left off.
+pub fn listen_socket(log: &Logger, base_dir: &str, socket_path: &str, ignore_strings: &[String]) -> Result<(), BindrsError> {
+    let listener = unix_socket::bind(socket_path)?;
+    info!(log, "Listening on {}", socket_path);
+
+    serve_each(log, listener.incoming(), |stream: UnixStream| {
+        info!(log, "Master attached");
+
+        let served = serve_connection(log, stream, |stream| {
+            let (ignores, reader, writer) = unix_socket::accept(stream, |master_ignores| {
+                let mut ignores = ignore_strings.to_vec();
+                ignores.extend(master_ignores);
+                helpers::process_ignores(&mut ignores)
+            })?;
+            Ok((base_dir.to_owned(), ignores, reader, writer))
+        });
+        match served {
+            Ok(()) |
+            Err(BindrsError::Disconnected(_)) => info!(log, "Master detached"),
+            Err(e) => warn!(log, "Master detached: {}", e),
+        }
+    });
+    Ok(())
+}
+
+/// Hands each connection that comes in to `serve_one`, carrying on past any that couldn't be
+/// accepted.
+fn serve_each<C, I, F>(log: &Logger, incoming: I, mut serve_one: F)
+where
+    I: Iterator<Item = io::Result<C>>,
+    F: FnMut(C),
+{
+    for stream in incoming {
+        match stream {
+            Ok(stream) => serve_one(stream),
+            Err(e) => warn!(log, "Failed to accept connection: {}", e),
+        }
+    }
+}
+
+/// Opens a session on `stream` with `accept`, which gives back the directory to sync, the
+/// ignores and the two halves of the connection, and serves it.
+fn serve_connection<C, R, W, F>(log: &Logger, stream: C, accept: F) -> Result<(), BindrsError>
+where
+    C: Connection,
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+    F: FnOnce(C) -> Result<(String, RegexSet, R, W), BindrsError>,
+{
+    let connection = connection::split(&stream)?;
+    let result = accept(stream).and_then(|(dir, ignores, reader, writer)| serve(log, &dir, ignores, reader, writer));
+
+    // Leftover session threads give up once the connection is gone
+    connection.shutdown(Shutdown::Both).unwrap_or_default();
+    result
+}
+
+/// The directory under `base_dir` a master asked for, which has to exist already.
+fn requested_dir(base_dir: &str, path: &str) -> Result<String, BindrsError> {
+    if path.is_empty() {
+        return Ok(base_dir.to_owned());
+    }
+
+    check_path(base_dir, path)?;
+    match helpers::resolve_path(&format!("{}/{}", base_dir, path)) {
+        // The last component could be a symlink leading anywhere
+        Some(ref dir) if !Path::new(dir).starts_with(base_dir) => Err(BindrsError::Path(format!(
+            "Rejected path {} from remote: path leads outside the synced directory through a symlink",
+            path
+        ))),
+        Some(ref dir) if Path::new(dir).is_dir() => Ok(dir.clone()),
+        _ => Err(BindrsError::Remote(format!("Remote directory {} does not exist, please create it", path))),
+    }
+}
+
+/// Syncs `base_dir` with the master on the other end of `reader` and `writer`.
+pub fn serve<R: Read + Send + 'static, W: Write + Send + 'static>(
+    log: &Logger,
+    base_dir: &str,
+    ignores: RegexSet,
+    reader: R,
+    writer: W,
+) -> Result<(), BindrsError> {
+    let (mut remote_reader, mut remote_writer) = (FrameReader::new(reader), FrameWriter::new(writer));
+    remote_writer.send(&Frame::Handshake(Handshake::local()))?;
+    remote_writer.flush()?;
+
+    let session = match remote_reader.recv()? {
+        (_, Frame::Handshake(handshake)) => {
+            info!(log, "Connected to {}", handshake.describe());
+            Session::from_handshake(&handshake)
+        }
+        _ => {
+            return Err(BindrsError::Protocol(
+                "Master did not reply with a handshake".to_owned(),
+            ))
+        }
+    };
+
+    remote_writer.set_codec(session.codec)?;
+
+    // Started before the manifest is taken so nothing changed after it is missed
+    let mut watcher = BindrsWatcher::new(base_dir, &ignores);
+    watcher.watch()?;
+    let local_changes = watcher.rx.take().ok_or_else(|| {
+        BindrsError::Watch("Couldn't get local receive channel off local watcher".to_owned())
+    })?;
+
+    let initial_changes = if session.initial_sync {
+        Manifest::scan(base_dir, &ignores).send(&mut remote_writer)?;
+        remote_writer.flush()?;
+        let changes = manifest::recv_pull(&mut remote_reader)?;
+        for &(_, ref path) in &changes {
+            check_path(base_dir, path)?;
+        }
+        changes
+    } else {
+        vec![]
+    };
+
+    executor::start(
+        log,
+        base_dir,
+        &ignores,
+        remote_reader,
+        remote_writer,
+        session,
+        initial_changes,
+        local_changes,
+        None,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs as unix_fs;
+    use test_support::test_dir;
+
+    #[test]
+    fn requested_dirs_stay_inside_base_dir() {
+        let (_dir, base_dir) = test_dir("bindrs-requested-dir");
+        fs::create_dir(format!("{}/inside", base_dir)).unwrap();
+        unix_fs::symlink("inside", format!("{}/alias", base_dir)).unwrap();
+        unix_fs::symlink("/tmp", format!("{}/escape", base_dir)).unwrap();
 
-    let (remote_reader, remote_writer) = (BufReader::new(io::stdin()), BufWriter::new(io::stdout()));
-    executor::start(log, base_dir, ignores, remote_reader, remote_writer);
+        assert_eq!(base_dir, requested_dir(&base_dir, "").unwrap());
+        assert_eq!(format!("{}/inside", base_dir), requested_dir(&base_dir, "inside").unwrap());
+        assert_eq!(format!("{}/inside", base_dir), requested_dir(&base_dir, "alias").unwrap());
+        match requested_dir(&base_dir, "escape") {
+            Err(BindrsError::Path(_)) => (),
+            _ => panic!("expected a symlink out of the base dir to be rejected"),
+        }
+        match requested_dir(&base_dir, "missing") {
+            Err(BindrsError::Remote(_)) => (),
+            _ => panic!("expected a missing dir to be reported"),
+        }
+    }
 }
@@ -1,131 +1,880 @@
-use bincode::{serialize, deserialize};
-use byteorder::{WriteBytesExt, ReadBytesExt, LittleEndian};
+use byteorder::{LittleEndian, WriteBytesExt};
+use error::BindrsError;
 use filetime::{self, FileTime};
 use std::fs::{self, File};
-use std::io::{Write, BufRead, Read};
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use slog::Logger;
+use std::io::{self, Read, Seek, SeekFrom, Write, BufReader};
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use structs::delta::{self, DeltaOp, Signatures, CHUNK_SIZE};
+use structs::frame::{Frame, FrameReader, FrameWriter};
+use structs::handshake::Session;
+use structs::owner::Owner;
+use structs::sparse::{self, SparseWriter};
+use structs::xattrs::Xattrs;
 
-#[derive(Serialize, Deserialize, PartialEq)]
+// New contents are written to a temp file ending in this, ignored in `helpers::process_ignores`
+const TEMP_SUFFIX: &str = ".bindrs-tmp";
+
+// Permission bits plus setuid, setgid and sticky
+const MODE_MASK: u32 = 0o7777;
+
+// Stands in for a hole in a file's content hash, followed by the hole's length
+const HOLE_MARKER: &[u8] = b"\0bindrs-hole\0";
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub enum FileAction {
     CreateUpdate,
     Delete,
+    Chmod,
+    Symlink,
+    CreateDir,
+    DeleteDir,
+    Rename { from: String },
+    HardLink { target: String },
 }
 
+/// Header for a synced file. For `CreateUpdate` the contents follow as `Frame::Chunk`s up to a
+/// `Frame::End` carrying the SHA-256 of the whole file, as a delta against the receiver's copy
+/// when `block_size` is set. For `Symlink` the link is recreated pointing at `link_target`. For `Rename` the file or directory at `from`
+/// is moved to `path`. For `HardLink` the file at `path` is replaced by a link to `target`.
 #[derive(Serialize, Deserialize, PartialEq)]
 pub struct BoundFile {
     pub action: FileAction,
     pub path: String,
-    pub mtime: i64,
-    pub contents: Vec<u8>,
+    pub mtime: Timestamp,
+    pub atime: Option<Timestamp>,
+    pub mode: u32,
+    pub block_size: Option<u32>,
+    pub link_target: Option<String>,
+    pub xattrs: Option<Xattrs>,
+    pub owner: Option<Owner>,
+}
+
+/// A file time at full filesystem precision.
+#[derive(Serialize, Deserialize, PartialEq, PartialOrd, Clone, Copy, Default, Debug)]
+pub struct Timestamp {
+    pub seconds: i64,
+    pub nanos: u32,
+}
+
+impl FileAction {
+    /// Actions that are applied from the path alone, without file metadata or contents.
+    fn is_metadata_free(&self) -> bool {
+        match *self {
+            FileAction::Delete |
+            FileAction::DeleteDir |
+            FileAction::Rename { .. } |
+            FileAction::HardLink { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 impl BoundFile {
-    pub fn to_writer<T: Write>(&self, writer: &mut T) {
-        let encoded = &self.encode()[..];
-
-        let len = encoded.len() as u64;
-        let mut wtr = vec![];
-        wtr.write_u64::<LittleEndian>(len).expect(
-            "Couldn't write stream length to remote!",
-        );
-
-        writer.write_all(&wtr[..]).expect(
-            "Couldn't write all bytes to remote!",
-        );
-        writer.write_all(encoded).expect(
-            "Couldn't write all bytes to remote!",
-        );
-        writer.flush().expect("Couldn't flush all bytes to remote!");
-    }
-
-    pub fn from_reader<T: BufRead>(reader: &mut T) -> Self {
-        let len: u64 = reader.read_u64::<LittleEndian>().expect(
-            "Couldn't read stream length from remote!",
-        );
-
-        let mut vec: Vec<u8> = vec![];
-        reader.take(len).read_to_end(&mut vec).expect(
-            "Couldn't read all bytes from remote!",
-        );
-        Self::decode(&vec[..])
-    }
-
-    pub fn build_from_path_action(base_dir: &str, path: String, action: FileAction) -> Self {
-        if action == FileAction::CreateUpdate {
-            // Write or Create
-            let mut vec: Vec<u8> = vec![];
-            let mut file = File::open(format!("{}/{}", base_dir, path)).expect(
-                "File does not exist locally, cannot build BoundFile",
-            );
-            file.read_to_end(&mut vec).expect(
-                "Failed to read local file contents into BoundFile",
-            );
-            let mtime = FileTime::from_last_modification_time(
-                &file.metadata().expect("Failed to read BoundFile metadata"),
-            ).unix_seconds();
-            Self {
+    /// Access times, extended attributes and owners are only read when the session preserves them.
+    pub fn build_from_path_action(
+        base_dir: &str,
+        path: String,
+        action: FileAction,
+        session: &Session,
+    ) -> Result<Self, BindrsError> {
+        if action.is_metadata_free() {
+            Ok(Self {
                 action,
                 path,
-                mtime,
-                contents: vec,
-            }
+                mtime: Timestamp::default(),
+                atime: None,
+                mode: 0,
+                block_size: None,
+                link_target: None,
+                xattrs: None,
+                owner: None,
+            })
+        } else if action == FileAction::Symlink {
+            let full_str_path = format!("{}/{}", base_dir, path);
+            let metadata = fs::symlink_metadata(&full_str_path).map_err(|_| {
+                BindrsError::File(format!("Symlink does not exist locally: {}", full_str_path))
+            })?;
+            let target = fs::read_link(&full_str_path).map_err(|_| {
+                BindrsError::File(format!("Failed to read symlink target: {}", full_str_path))
+            })?;
+            Ok(Self {
+                action,
+                path,
+                mtime: Timestamp::from(FileTime::from_last_modification_time(&metadata)),
+                atime: None,
+                mode: 0,
+                block_size: None,
+                link_target: Some(target.to_string_lossy().into_owned()),
+                xattrs: None,
+                owner: Self::owner_from_metadata(&metadata, session),
+            })
         } else {
-            // Delete
-            Self {
+            // Write, Create, Chmod or CreateDir
+            let full_str_path = format!("{}/{}", base_dir, path);
+            let metadata = fs::metadata(&full_str_path).map_err(|_| {
+                BindrsError::File(format!("File does not exist locally: {}", full_str_path))
+            })?;
+            let atime = if session.preserve_atimes {
+                Some(Timestamp::from(FileTime::from_last_access_time(&metadata)))
+            } else {
+                None
+            };
+            // Left out on filesystems without extended attribute support, so the remote keeps its own
+            let xattrs = if session.xattr_namespaces.is_empty() {
+                None
+            } else {
+                Xattrs::read(Path::new(&full_str_path), session.xattr_namespaces).ok()
+            };
+            Ok(Self {
                 action,
                 path,
-                mtime: 0,
-                contents: vec![],
+                mtime: Timestamp::from(FileTime::from_last_modification_time(&metadata)),
+                atime,
+                mode: metadata.permissions().mode() & MODE_MASK,
+                block_size: None,
+                link_target: None,
+                xattrs,
+                owner: Self::owner_from_metadata(&metadata, session),
+            })
+        }
+    }
+
+    fn owner_from_metadata(metadata: &fs::Metadata, session: &Session) -> Option<Owner> {
+        if session.preserve_owners {
+            Some(session.owner_map.to_remote(Owner::from_metadata(metadata)))
+        } else {
+            None
+        }
+    }
+
+    pub fn signatures_from_disk(base_dir: &str, path: &str) -> Option<Signatures> {
+        let full_str_path = format!("{}/{}", base_dir, path);
+        match fs::symlink_metadata(&full_str_path) {
+            Ok(ref metadata) if metadata.is_file() => (),
+            _ => return None,
+        }
+
+        File::open(&full_str_path).ok().and_then(
+            |file| Signatures::build_from_file(&file).ok(),
+        )
+    }
+
+    /// Sends the header and any contents, returning the sequence number of the header.
+    pub fn to_writer<T: Write>(
+        mut self,
+        base_dir: &str,
+        writer: &mut FrameWriter<T>,
+        signatures: Option<&Signatures>,
+    ) -> Result<u64, BindrsError> {
+        let full_str_path = format!("{}/{}", base_dir, self.path);
+        if self.action != FileAction::CreateUpdate {
+            return writer.send(&Frame::File(self));
+        }
+
+        let file = File::open(&full_str_path).map_err(|_| {
+            BindrsError::File(format!("Failed to open file at: {}", full_str_path))
+        })?;
+        let mut file = Hashing::new(BufReader::new(file));
+        self.block_size = signatures.map(|sigs| sigs.block_size);
+        // Holes are only skipped when sending whole files, a delta reads them as zeros
+        let sparse = signatures.is_none() && file.inner.get_ref().metadata().map(|m| sparse::is_sparse(&m)).unwrap_or(false);
+        let seq = writer.send(&Frame::File(self))?;
+
+        let sent = match signatures {
+            Some(sigs) => delta::build_delta(sigs, &mut file, |op| writer.send(&Frame::Chunk(op)).map(|_| ())),
+            None if sparse => Self::send_sparse_chunks(&mut file, writer),
+            None => Self::send_chunks(&mut file, writer),
+        };
+        match sent {
+            Ok(()) => {
+                writer.send(&Frame::End(file.finish().1))?;
+            }
+            // The remote is expecting the rest of the file, so end it with a hash it can't match
+            // and let the failed acknowledgement bring about a retry
+            Err(BindrsError::File(_)) => {
+                writer.send(&Frame::End(vec![]))?;
             }
+            Err(e) => return Err(e),
         }
+        Ok(seq)
     }
 
-    pub fn save_to_disk(&self, base_dir: &str) {
+    fn send_chunks<R: Read, T: Write>(file: &mut R, writer: &mut FrameWriter<T>) -> Result<(), BindrsError> {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            let read = delta::read_block(file, &mut buf).map_err(|e| {
+                BindrsError::File(format!("Failed to read local file contents: {}", e))
+            })?;
+            if read == 0 {
+                return Ok(());
+            }
+            writer.send(&Frame::Chunk(DeltaOp::Literal(buf[..read].to_vec())))?;
+        }
+    }
+
+    /// Sends only the data of a sparse file, with each hole in it as a `DeltaOp::Hole`.
+    fn send_sparse_chunks<T: Write>(file: &mut Hashing<BufReader<File>>, writer: &mut FrameWriter<T>) -> Result<(), BindrsError> {
+        let read_error = |e: io::Error| BindrsError::File(format!("Failed to find holes in local file: {}", e));
+        let len = file.inner.get_ref().metadata().map_err(&read_error)?.len();
+        let mut offset = 0;
+        while offset < len {
+            let (start, end) = sparse::next_data(file.inner.get_ref(), offset, len).map_err(&read_error)?;
+            if start > offset {
+                file.hash_hole(start - offset);
+                writer.send(&Frame::Chunk(DeltaOp::Hole(start - offset)))?;
+            }
+            if start < end {
+                file.inner.seek(SeekFrom::Start(start)).map_err(&read_error)?;
+                Self::send_chunks(&mut file.by_ref().take(end - start), writer)?;
+            }
+            offset = end;
+        }
+        Ok(())
+    }
+
+    /// Applies the action to disk. Any contents are read off `reader` in full even when applying
+    /// fails, so the stream stays in step with the sender.
+    pub fn save_to_disk<T: Read>(&self, log: &Logger, base_dir: &str, reader: &mut FrameReader<T>) -> Result<(), BindrsError> {
+        let checked = match self.action {
+            FileAction::Rename { from: ref other } |
+            FileAction::HardLink { target: ref other } => {
+                check_path(base_dir, other).and_then(|_| check_path(base_dir, &self.path))
+            }
+            _ => check_path(base_dir, &self.path),
+        };
+        if let Err(e) = checked {
+            if self.action == FileAction::CreateUpdate {
+                Self::skip_chunks(reader)?;
+            }
+            return Err(e);
+        }
+
         let full_str_path = format!("{}/{}", base_dir, self.path);
         let full_path = Path::new(&full_str_path);
-        let mut file_exists = full_path.exists();
-        if file_exists && full_path.is_dir() {
-            fs::remove_dir_all(&full_path).unwrap_or_else(|_| {
-                panic!(
-                    "Failed to remove folder where file should be: {}",
-                    full_str_path
-                )
-            });
-            file_exists = false;
+        match self.action {
+            FileAction::CreateUpdate => {
+                if let Err(e) = Self::prepare_file_path(full_path) {
+                    Self::skip_chunks(reader)?;
+                    return Err(e);
+                }
+                self.save_contents(log, full_path, reader)
+            }
+            FileAction::Chmod => {
+                if full_path.exists() && !is_symlink(full_path) {
+                    self.set_attributes(log, full_path)
+                } else {
+                    Ok(())
+                }
+            }
+            FileAction::Symlink => {
+                Self::remove_path(full_path)?;
+                Self::prepare_file_path(full_path)?;
+                let target = self.link_target.as_ref().ok_or_else(|| {
+                    BindrsError::File(format!("Received symlink without a target: {}", full_str_path))
+                })?;
+                unix_fs::symlink(target, full_path).map_err(|_| {
+                    BindrsError::File(format!("Failed to create symlink at: {}", full_str_path))
+                })?;
+                self.set_owner(full_path)
+            }
+            FileAction::CreateDir |
+            FileAction::DeleteDir => self.save_directory(log, full_path),
+            FileAction::Rename { ref from } => Self::rename(&format!("{}/{}", base_dir, from), full_path),
+            FileAction::HardLink { ref target } => Self::hard_link(&format!("{}/{}", base_dir, target), full_path),
+            FileAction::Delete => Self::remove_path(full_path),
         }
+    }
 
-        if self.action == FileAction::CreateUpdate {
-            // Write or Create
-            let parent = full_path.parent().unwrap_or_else(|| {
-                panic!("Failed to get parent for: {}", full_str_path)
-            });
-            fs::create_dir_all(&parent).unwrap_or_else(|_| {
-                panic!("Failed to create parent directory for: {}", full_str_path)
-            });
-            let mut file = File::create(&full_path).unwrap_or_else(|_| {
-                panic!("Failed to open/create file at: {}", full_str_path)
+    /// Builds the new contents in a temp file beside the original, with its attributes and times
+    /// already set, and only then renames it into place so nothing watching the directory ever
+    /// sees a partial file.
+    fn save_contents<T: Read>(&self, log: &Logger, full_path: &Path, reader: &mut FrameReader<T>) -> Result<(), BindrsError> {
+        let temp_path = temp_path_for(full_path);
+        let saved = match self.block_size {
+            Some(block_size) => Self::write_delta(full_path, &temp_path, block_size, reader),
+            None => Self::write_chunks(full_path, &temp_path, reader),
+        }.and_then(|_| self.set_attributes(log, &temp_path))
+            .and_then(|_| self.set_times(&temp_path))
+            .and_then(|_| {
+                fs::rename(&temp_path, full_path).map_err(|_| {
+                    BindrsError::File(format!("Failed to move new contents into place at: {}", full_path.display()))
+                })
             });
-            file.write_all(&self.contents[..]).unwrap_or_else(|_| {
-                panic!("Failed to write all bytes to: {}", full_str_path)
-            });
-            file.sync_all().unwrap_or_else(|_| {
-                panic!("Failed to sync contents at: {}", full_str_path)
+
+        if saved.is_err() {
+            // Leave the existing copy alone rather than moving a bad one into place
+            fs::remove_file(&temp_path).unwrap_or_default();
+        }
+        saved
+    }
+
+    fn set_times(&self, full_path: &Path) -> Result<(), BindrsError> {
+        let set_times = match self.atime {
+            Some(atime) => filetime::set_file_times(full_path, atime.into(), self.mtime.into()),
+            None => filetime::set_file_mtime(full_path, self.mtime.into()),
+        };
+        set_times.map_err(|_| BindrsError::File(format!("Failed to set file time at: {}", full_path.display())))
+    }
+
+    /// Clears a symlink or directory out of the way of a file and makes sure its parent exists.
+    fn prepare_file_path(full_path: &Path) -> Result<(), BindrsError> {
+        if is_symlink(full_path) || full_path.is_dir() {
+            Self::remove_path(full_path)?;
+        }
+
+        let parent = full_path.parent().ok_or_else(|| {
+            BindrsError::File(format!("Failed to get parent for: {}", full_path.display()))
+        })?;
+        fs::create_dir_all(&parent).map_err(|_| {
+            BindrsError::File(format!("Failed to create parent directory for: {}", full_path.display()))
+        })
+    }
+
+    /// Removes whatever is at the path, never following a symlink.
+    fn remove_path(full_path: &Path) -> Result<(), BindrsError> {
+        let removed = match fs::symlink_metadata(&full_path) {
+            Err(_) => return Ok(()),
+            Ok(ref metadata) if metadata.is_dir() => fs::remove_dir_all(&full_path),
+            Ok(_) => fs::remove_file(&full_path),
+        };
+        removed.map_err(|_| BindrsError::File(format!("Failed to delete at: {}", full_path.display())))
+    }
+
+    fn rename(from_str_path: &str, full_path: &Path) -> Result<(), BindrsError> {
+        if fs::symlink_metadata(&from_str_path).is_err() {
+            return Err(BindrsError::File(format!(
+                "Can't rename {} to {} as it does not exist",
+                from_str_path,
+                full_path.display()
+            )));
+        }
+
+        Self::remove_path(full_path)?;
+        let parent = full_path.parent().ok_or_else(|| {
+            BindrsError::File(format!("Failed to get parent for: {}", full_path.display()))
+        })?;
+        fs::create_dir_all(&parent).map_err(|_| {
+            BindrsError::File(format!("Failed to create parent directory for: {}", full_path.display()))
+        })?;
+        fs::rename(from_str_path, full_path).map_err(|_| {
+            BindrsError::File(format!("Failed to rename {} to: {}", from_str_path, full_path.display()))
+        })
+    }
+
+    fn hard_link(target_str_path: &str, full_path: &Path) -> Result<(), BindrsError> {
+        let target = fs::symlink_metadata(&target_str_path).map_err(|_| {
+            BindrsError::File(format!("Can't link {} to missing: {}", full_path.display(), target_str_path))
+        })?;
+        if let Ok(existing) = fs::symlink_metadata(&full_path) {
+            if existing.dev() == target.dev() && existing.ino() == target.ino() {
+                return Ok(());
+            }
+        }
+
+        Self::remove_path(full_path)?;
+        Self::prepare_file_path(full_path)?;
+        fs::hard_link(target_str_path, full_path).map_err(|_| {
+            BindrsError::File(format!("Failed to link {} to: {}", full_path.display(), target_str_path))
+        })
+    }
+
+    fn save_directory(&self, log: &Logger, full_path: &Path) -> Result<(), BindrsError> {
+        if self.action == FileAction::DeleteDir {
+            if is_symlink(full_path) || full_path.is_dir() {
+                Self::remove_path(full_path)?;
+            }
+            return Ok(());
+        }
+
+        if is_symlink(full_path) || (full_path.exists() && !full_path.is_dir()) {
+            Self::remove_path(full_path)?;
+        }
+        fs::create_dir_all(&full_path).map_err(|_| {
+            BindrsError::File(format!("Failed to create directory at: {}", full_path.display()))
+        })?;
+        self.set_attributes(log, full_path)
+    }
+
+    fn set_attributes(&self, log: &Logger, full_path: &Path) -> Result<(), BindrsError> {
+        // Changing owner can clear setuid and setgid, so it goes before the mode
+        self.set_owner(full_path)?;
+        fs::set_permissions(full_path, fs::Permissions::from_mode(self.mode)).map_err(|_| {
+            BindrsError::File(format!("Failed to set permissions at: {}", full_path.display()))
+        })?;
+
+        // Not every filesystem or user can set every attribute, and the contents matter more
+        if let Some(ref xattrs) = self.xattrs {
+            if let Err(e) = xattrs.apply(full_path) {
+                warn!(log, "Failed to set extended attributes on {}: {}", self.path, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_owner(&self, full_path: &Path) -> Result<(), BindrsError> {
+        match self.owner {
+            Some(ref owner) => owner.apply(full_path),
+            None => Ok(()),
+        }
+    }
+
+    fn write_chunks<T: Read>(full_path: &Path, temp_path: &Path, reader: &mut FrameReader<T>) -> Result<(), BindrsError> {
+        let file = match File::create(temp_path) {
+            Ok(file) => file,
+            Err(_) => {
+                Self::skip_chunks(reader)?;
+                return Err(BindrsError::File(format!("Failed to open/create file at: {}", temp_path.display())));
+            }
+        };
+        let mut writer = Hashing::new(SparseWriter::new(file));
+        let (expected_hash, written) = Self::read_chunks(reader, |op| match op {
+            DeltaOp::Literal(bytes) => writer.write_all(&bytes[..]),
+            DeltaOp::Hole(len) => {
+                writer.hash_hole(len);
+                writer.inner.skip(len);
+                Ok(())
+            }
+            DeltaOp::Copy(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "delta block for non-delta file")),
+        })?;
+        written.map_err(|_| BindrsError::File(format!("Failed to write all bytes to: {}", full_path.display())))?;
+
+        let (writer, hash) = writer.finish();
+        Self::sync_writer(writer, full_path)?;
+        Self::verify(full_path, &hash, &expected_hash)
+    }
+
+    fn write_delta<T: Read>(
+        full_path: &Path,
+        temp_path: &Path,
+        block_size: u32,
+        reader: &mut FrameReader<T>,
+    ) -> Result<(), BindrsError> {
+        delta::check_block_size(block_size)?;
+        let opened = File::open(&full_path)
+            .map_err(|_| BindrsError::File(format!("Failed to open delta basis at: {}", full_path.display())))
+            .and_then(|basis| {
+                File::create(temp_path)
+                    .map(|file| (basis, file))
+                    .map_err(|_| BindrsError::File(format!("Failed to open/create file at: {}", temp_path.display())))
             });
+        let (mut basis, file) = match opened {
+            Ok(files) => files,
+            Err(e) => {
+                Self::skip_chunks(reader)?;
+                return Err(e);
+            }
+        };
+        let mut writer = Hashing::new(SparseWriter::new(file));
+        let mut block = vec![0u8; block_size as usize];
+        let (expected_hash, written) = Self::read_chunks(reader, |op| op.apply(&mut block, &mut basis, &mut writer))?;
+        written.map_err(|_| BindrsError::File(format!("Failed to apply delta to: {}", full_path.display())))?;
+
+        let (writer, hash) = writer.finish();
+        Self::sync_writer(writer, full_path)?;
+        Self::verify(full_path, &hash, &expected_hash)
+    }
+
+    /// Feeds each received chunk to `apply` and returns the content hash the sender computed.
+    /// Chunks after a failed `apply` are read and dropped. Only a broken stream is an `Err`.
+    fn read_chunks<T: Read, F: FnMut(DeltaOp) -> io::Result<()>>(
+        reader: &mut FrameReader<T>,
+        mut apply: F,
+    ) -> Result<(Vec<u8>, io::Result<()>), BindrsError> {
+        let mut result = Ok(());
+        loop {
+            match reader.recv()? {
+                (_, Frame::Chunk(op)) => {
+                    if result.is_ok() {
+                        result = apply(op);
+                    }
+                }
+                (_, Frame::End(hash)) => return Ok((hash, result)),
+                _ => {
+                    return Err(BindrsError::Protocol(
+                        "Received unexpected frame while receiving file contents".to_owned(),
+                    ))
+                }
+            }
+        }
+    }
+
+    fn skip_chunks<T: Read>(reader: &mut FrameReader<T>) -> Result<(), BindrsError> {
+        Self::read_chunks(reader, |_| Ok(())).map(|_| ())
+    }
+
+    fn verify(full_path: &Path, hash: &[u8], expected_hash: &[u8]) -> Result<(), BindrsError> {
+        if hash == expected_hash {
+            Ok(())
+        } else {
+            Err(BindrsError::File(format!("Content hash mismatch after writing {}", full_path.display())))
+        }
+    }
+
+    fn sync_writer(writer: SparseWriter, full_path: &Path) -> Result<(), BindrsError> {
+        let file = writer.finish().map_err(|_| {
+            BindrsError::File(format!("Failed to size file at: {}", full_path.display()))
+        })?;
+        let file = file.into_inner().map_err(|_| {
+            BindrsError::File(format!("Failed to write all bytes to: {}", full_path.display()))
+        })?;
+        file.sync_all().map_err(|_| {
+            BindrsError::File(format!("Failed to sync contents at: {}", full_path.display()))
+        })
+    }
+}
+
+impl From<FileTime> for Timestamp {
+    fn from(time: FileTime) -> Self {
+        Self {
+            seconds: time.unix_seconds(),
+            nanos: time.nanoseconds(),
+        }
+    }
+}
+
+impl From<Timestamp> for FileTime {
+    fn from(time: Timestamp) -> Self {
+        FileTime::from_unix_time(time.seconds, time.nanos)
+    }
+}
+
+/// Wraps a reader or writer, hashing every byte that passes through it.
+struct Hashing<T> {
+    inner: T,
+    hasher: Sha256,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::default(),
+        }
+    }
+
+    fn finish(self) -> (T, Vec<u8>) {
+        (self.inner, self.hasher.result().to_vec())
+    }
+
+    /// Hashes a hole by its length, as hashing it as zeros would cost as much as reading them.
+    fn hash_hole(&mut self, len: u64) {
+        let mut marker = HOLE_MARKER.to_vec();
+        // Writes to a Vec can't fail
+        marker.write_u64::<LittleEndian>(len).unwrap_or_default();
+        self.hasher.input(&marker[..]);
+    }
+}
+
+impl<T: Read> Read for Hashing<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.input(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Hashing<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.input(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Makes sure a path from the remote stays inside `base_dir`: it must be relative, without `.` or
+/// `..` components, and its existing parent directories must not lead outside through a symlink.
+pub fn check_path(base_dir: &str, path: &str) -> Result<(), BindrsError> {
+    let unsafe_path = |reason: &str| Err(BindrsError::Path(format!("Rejected path {} from remote: {}", path, reason)));
+    let relative = Path::new(path);
+    if path.is_empty() {
+        return unsafe_path("path is empty");
+    }
+    for component in relative.components() {
+        match component {
+            Component::Normal(_) => (),
+            Component::ParentDir => return unsafe_path("path contains .."),
+            Component::RootDir |
+            Component::Prefix(_) => return unsafe_path("path is absolute"),
+            Component::CurDir => return unsafe_path("path contains ."),
+        }
+    }
+
+    // Only the deepest directory that already exists needs resolving, the rest will be created
+    let base_path = Path::new(base_dir);
+    let mut parent = relative.parent();
+    while let Some(dir) = parent {
+        if let Ok(resolved) = fs::canonicalize(base_path.join(dir)) {
+            if !resolved.starts_with(base_path) {
+                return unsafe_path("path leads outside the synced directory through a symlink");
+            }
+            break;
+        }
+        parent = dir.parent();
+    }
+    Ok(())
+}
+
+/// Where new contents for `full_path` are built before being moved over it. Kept in the same
+/// directory so the rename stays on one filesystem.
+fn temp_path_for(full_path: &Path) -> PathBuf {
+    let name = full_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    full_path.with_file_name(format!(".{}{}", name, TEMP_SUFFIX))
+}
+
+pub fn is_symlink(path: &Path) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata.file_type().is_symlink(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structs::handshake::Handshake;
+    use structs::pipe::pipe;
+    use structs::xattrs::XattrNamespaces;
+    use test_support::{receive, send, sync, test_dir};
+    use xattr;
+
+    fn mode(path: &str) -> u32 {
+        fs::symlink_metadata(path).unwrap().permissions().mode() & MODE_MASK
+    }
+
+    #[test]
+    fn mode_bits_survive_a_round_trip() {
+        let (_src, src_dir) = test_dir("bindrs-bound-file-src");
+        let (_dst, dst_dir) = test_dir("bindrs-bound-file-dst");
+        let session = Session::from_handshake(&Handshake::local());
+        let (src, dst) = (format!("{}/run.sh", src_dir), format!("{}/run.sh", dst_dir));
+        fs::write(&src, b"#!/bin/sh").unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o2751)).unwrap();
+
+        sync(&src_dir, &dst_dir, "run.sh", FileAction::CreateUpdate, &session).unwrap();
+        assert_eq!(b"#!/bin/sh".to_vec(), fs::read(&dst).unwrap());
+        assert_eq!(0o2751, mode(&dst));
+
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o600)).unwrap();
+        sync(&src_dir, &dst_dir, "run.sh", FileAction::Chmod, &session).unwrap();
+        assert_eq!(0o600, mode(&dst));
+    }
+
+    #[test]
+    fn symlinks_are_recreated_as_links_or_followed() {
+        let (_src, src_dir) = test_dir("bindrs-bound-file-src");
+        let (_dst, dst_dir) = test_dir("bindrs-bound-file-dst");
+        let session = Session::from_handshake(&Handshake::local());
+        fs::write(format!("{}/target", src_dir), b"pointed at").unwrap();
+        unix_fs::symlink("target", format!("{}/link", src_dir)).unwrap();
+        unix_fs::symlink("missing", format!("{}/dangling", src_dir)).unwrap();
+
+        sync(&src_dir, &dst_dir, "link", FileAction::Symlink, &session).unwrap();
+        sync(&src_dir, &dst_dir, "dangling", FileAction::Symlink, &session).unwrap();
+        assert_eq!(Path::new("target"), fs::read_link(format!("{}/link", dst_dir)).unwrap());
+        assert_eq!(Path::new("missing"), fs::read_link(format!("{}/dangling", dst_dir)).unwrap());
+
+        let (_followed, followed_dir) = test_dir("bindrs-bound-file-dst");
+        let follow = Session {
+            preserve_symlinks: false,
+            ..Session::from_handshake(&Handshake::local())
+        };
+        sync(&src_dir, &followed_dir, "link", FileAction::CreateUpdate, &follow).unwrap();
+        let link = format!("{}/link", followed_dir);
+        assert!(!is_symlink(Path::new(&link)));
+        assert_eq!(b"pointed at".to_vec(), fs::read(&link).unwrap());
+        match sync(&src_dir, &followed_dir, "dangling", FileAction::CreateUpdate, &follow) {
+            Err(BindrsError::File(_)) => (),
+            _ => panic!("expected a dangling link to have nothing to send"),
+        }
+        assert!(fs::symlink_metadata(format!("{}/dangling", followed_dir)).is_err());
+    }
+
+    #[test]
+    fn directories_are_created_and_deleted() {
+        let (_src, src_dir) = test_dir("bindrs-bound-file-src");
+        let (_dst, dst_dir) = test_dir("bindrs-bound-file-dst");
+        let session = Session::from_handshake(&Handshake::local());
+        let (src, dst) = (format!("{}/a/empty", src_dir), format!("{}/a/empty", dst_dir));
+        fs::create_dir_all(&src).unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o750)).unwrap();
+
+        sync(&src_dir, &dst_dir, "a/empty", FileAction::CreateDir, &session).unwrap();
+        assert!(Path::new(&dst).is_dir());
+        assert_eq!(0o750, mode(&dst));
+
+        fs::write(format!("{}/left-behind", dst), b"").unwrap();
+        fs::remove_dir(&src).unwrap();
+        sync(&src_dir, &dst_dir, "a/empty", FileAction::DeleteDir, &session).unwrap();
+        assert!(!Path::new(&dst).exists());
+        assert!(Path::new(&format!("{}/a", dst_dir)).is_dir());
+    }
 
-            let file_time = FileTime::from_unix_time(self.mtime, 0);
-            filetime::set_file_times(full_path, file_time, file_time)
-                .unwrap_or_else(|_| panic!("Failed to set file time at: {}", full_str_path));
-        } else if file_exists {
-            // Delete
-            fs::remove_file(&full_path).unwrap_or_else(|_| panic!("Failed to delete file at: {}", full_str_path));
+    #[test]
+    fn files_and_directories_are_renamed() {
+        let (_src, src_dir) = test_dir("bindrs-bound-file-src");
+        let (_dst, dst_dir) = test_dir("bindrs-bound-file-dst");
+        let session = Session::from_handshake(&Handshake::local());
+        fs::write(format!("{}/a", dst_dir), b"file").unwrap();
+        fs::create_dir(format!("{}/d", dst_dir)).unwrap();
+        fs::write(format!("{}/d/x", dst_dir), b"inside").unwrap();
+
+        let rename = |from: &str| FileAction::Rename { from: from.to_owned() };
+        sync(&src_dir, &dst_dir, "b", rename("a"), &session).unwrap();
+        sync(&src_dir, &dst_dir, "moved/e", rename("d"), &session).unwrap();
+        assert!(!Path::new(&format!("{}/a", dst_dir)).exists());
+        assert!(!Path::new(&format!("{}/d", dst_dir)).exists());
+        assert_eq!(b"file".to_vec(), fs::read(format!("{}/b", dst_dir)).unwrap());
+        assert_eq!(b"inside".to_vec(), fs::read(format!("{}/moved/e/x", dst_dir)).unwrap());
+
+        match sync(&src_dir, &dst_dir, "c", rename("a"), &session) {
+            Err(BindrsError::File(_)) => (),
+            _ => panic!("expected renaming a missing source to fail"),
+        }
+    }
+
+    #[test]
+    fn corrupted_contents_leave_the_destination_untouched() {
+        let (_src, src_dir) = test_dir("bindrs-bound-file-src");
+        let (_dst, dst_dir) = test_dir("bindrs-bound-file-dst");
+        let session = Session::from_handshake(&Handshake::local());
+        fs::write(format!("{}/f", src_dir), b"new contents").unwrap();
+        fs::write(format!("{}/f", dst_dir), b"old contents").unwrap();
+
+        let (reader, writer) = pipe();
+        let mut writer = FrameWriter::new(writer);
+        let bound_file = BoundFile::build_from_path_action(&src_dir, "f".to_owned(), FileAction::CreateUpdate, &session).unwrap();
+        writer.send(&Frame::File(bound_file)).unwrap();
+        writer.send(&Frame::Chunk(DeltaOp::Literal(b"new contents".to_vec()))).unwrap();
+        writer.send(&Frame::End(vec![0u8; 32])).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        match receive(&dst_dir, FrameReader::new(reader)) {
+            Err(BindrsError::File(_)) => (),
+            _ => panic!("expected a hash mismatch"),
         }
+        assert_eq!(b"old contents".to_vec(), fs::read(format!("{}/f", dst_dir)).unwrap());
+        assert_eq!(1, fs::read_dir(&dst_dir).unwrap().count());
     }
 
-    fn encode(&self) -> Vec<u8> {
-        serialize(&self).expect("Failed to encode BoundFile")
+    #[test]
+    fn contents_replace_the_file_rather_than_writing_through_it() {
+        let (_src, src_dir) = test_dir("bindrs-bound-file-src");
+        let (_dst, dst_dir) = test_dir("bindrs-bound-file-dst");
+        let session = Session::from_handshake(&Handshake::local());
+        let dst = format!("{}/f", dst_dir);
+        fs::write(format!("{}/f", src_dir), b"new contents").unwrap();
+        fs::write(&dst, b"old contents").unwrap();
+        fs::hard_link(&dst, format!("{}/old", dst_dir)).unwrap();
+
+        // A block copy has no place in a whole file, so writing fails part way through
+        let (reader, writer) = pipe();
+        let mut writer = FrameWriter::new(writer);
+        let bound_file = BoundFile::build_from_path_action(&src_dir, "f".to_owned(), FileAction::CreateUpdate, &session).unwrap();
+        writer.send(&Frame::File(bound_file)).unwrap();
+        writer.send(&Frame::Chunk(DeltaOp::Literal(b"new".to_vec()))).unwrap();
+        writer.send(&Frame::Chunk(DeltaOp::Copy(0))).unwrap();
+        writer.send(&Frame::End(vec![])).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        match receive(&dst_dir, FrameReader::new(reader)) {
+            Err(BindrsError::File(_)) => (),
+            _ => panic!("expected the write to fail"),
+        }
+        assert_eq!(b"old contents".to_vec(), fs::read(&dst).unwrap());
+        assert!(!temp_path_for(Path::new(&dst)).exists());
+
+        sync(&src_dir, &dst_dir, "f", FileAction::CreateUpdate, &session).unwrap();
+        assert_eq!(b"new contents".to_vec(), fs::read(&dst).unwrap());
+        assert_eq!(b"old contents".to_vec(), fs::read(format!("{}/old", dst_dir)).unwrap());
+        assert_eq!(2, fs::read_dir(&dst_dir).unwrap().count());
     }
 
-    fn decode(bytes: &[u8]) -> Self {
-        deserialize(bytes).expect("Failed to decode BoundFile")
+    #[test]
+    fn sparse_files_keep_their_holes() {
+        let (_src, src_dir) = test_dir("bindrs-bound-file-src");
+        let (_dst, dst_dir) = test_dir("bindrs-bound-file-dst");
+        let session = Session::from_handshake(&Handshake::local());
+        let src = format!("{}/sparse", src_dir);
+        let mut file = File::create(&src).unwrap();
+        file.set_len(16 * 1024 * 1024).unwrap();
+        file.seek(SeekFrom::Start(8 * 1024 * 1024)).unwrap();
+        file.write_all(b"middle").unwrap();
+        drop(file);
+
+        sync(&src_dir, &dst_dir, "sparse", FileAction::CreateUpdate, &session).unwrap();
+        let dst = format!("{}/sparse", dst_dir);
+        assert!(fs::read(&src).unwrap() == fs::read(&dst).unwrap());
+        // Elsewhere holes can't be found, so they're sent as zeros
+        if cfg!(any(target_os = "dragonfly", target_os = "freebsd", target_os = "linux")) {
+            assert!(sparse::is_sparse(&fs::metadata(&dst).unwrap()));
+        }
+    }
+
+    #[test]
+    fn mtimes_keep_their_nanoseconds() {
+        let (_src, src_dir) = test_dir("bindrs-bound-file-src");
+        let (_dst, dst_dir) = test_dir("bindrs-bound-file-dst");
+        let session = Session::from_handshake(&Handshake::local());
+        let src = format!("{}/f", src_dir);
+        fs::write(&src, b"contents").unwrap();
+        let mtime = FileTime::from_unix_time(1_500_000_000, 123_456_789);
+        filetime::set_file_times(&src, mtime, mtime).unwrap();
+
+        sync(&src_dir, &dst_dir, "f", FileAction::CreateUpdate, &session).unwrap();
+        let metadata = fs::metadata(format!("{}/f", dst_dir)).unwrap();
+        assert_eq!(mtime, FileTime::from_last_modification_time(&metadata));
+    }
+
+    #[test]
+    fn unsettable_xattrs_still_save_the_contents() {
+        let (_src, src_dir) = test_dir("bindrs-bound-file-src");
+        let (_dst, dst_dir) = test_dir("bindrs-bound-file-dst");
+        let session = Session::from_handshake(&Handshake::local());
+        fs::write(format!("{}/f", src_dir), b"contents").unwrap();
+
+        let mut bound_file = BoundFile::build_from_path_action(&src_dir, "f".to_owned(), FileAction::CreateUpdate, &session).unwrap();
+        // Not a valid ACL, so no filesystem takes it
+        bound_file.xattrs = Some(Xattrs {
+            namespaces: XattrNamespaces::all(),
+            attrs: vec![
+                ("system.posix_acl_access".to_owned(), b"bogus".to_vec()),
+                ("user.tag".to_owned(), b"kept".to_vec()),
+            ],
+        });
+        receive(&dst_dir, send(&src_dir, bound_file).unwrap()).unwrap();
+        let dst = format!("{}/f", dst_dir);
+        assert_eq!(b"contents".to_vec(), fs::read(&dst).unwrap());
+        // Attributes that can be set still are, where the filesystem has a user namespace
+        if xattr::set(&format!("{}/f", src_dir), "user.probe", b"").is_ok() {
+            assert_eq!(Some(b"kept".to_vec()), xattr::get(&dst, "user.tag").unwrap());
+        }
+    }
+
+    #[test]
+    fn paths_outside_base_dir_are_rejected() {
+        let (_dir, base_dir) = test_dir("bindrs-check-path");
+        fs::create_dir(format!("{}/inside", base_dir)).unwrap();
+        unix_fs::symlink("/tmp", format!("{}/escape", base_dir)).unwrap();
+        unix_fs::symlink("inside", format!("{}/alias", base_dir)).unwrap();
+
+        assert!(check_path(&base_dir, "inside/file").is_ok());
+        assert!(check_path(&base_dir, "new/dirs/file").is_ok());
+        assert!(check_path(&base_dir, "alias/file").is_ok());
+        assert!(check_path(&base_dir, "escape").is_ok());
+        assert!(check_path(&base_dir, "").is_err());
+        assert!(check_path(&base_dir, "../file").is_err());
+        assert!(check_path(&base_dir, "inside/../../file").is_err());
+        assert!(check_path(&base_dir, "/etc/passwd").is_err());
+        assert!(check_path(&base_dir, "escape/file").is_err());
+        assert!(check_path(&base_dir, "escape/new/file").is_err());
     }
 }
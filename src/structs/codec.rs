@@ -0,0 +1,140 @@
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::io::{self, Read, Write};
+
+const RAW_TAG: u8 = 0;
+const DEFLATE_TAG: u8 = 1;
+const MIN_COMPRESS_SIZE: usize = 128;
+const SAMPLE_SIZE: usize = 4096;
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum Codec {
+    None,
+    Deflate,
+}
+
+impl Codec {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Codec::None),
+            "deflate" => Some(Codec::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Deflate => "deflate",
+        }
+    }
+
+    /// Returns the tag and payload to put on the wire, falling back to raw bytes when
+    /// compressing would not pay off.
+    pub fn encode(self, bytes: &[u8]) -> (u8, Vec<u8>) {
+        if self == Codec::None || bytes.len() < MIN_COMPRESS_SIZE || looks_incompressible(bytes) {
+            return (RAW_TAG, bytes.to_vec());
+        }
+
+        match deflate(bytes, Compression::default()) {
+            Ok(compressed) => {
+                if compressed.len() < bytes.len() {
+                    (DEFLATE_TAG, compressed)
+                } else {
+                    (RAW_TAG, bytes.to_vec())
+                }
+            }
+            Err(_) => (RAW_TAG, bytes.to_vec()),
+        }
+    }
+}
+
+/// Fails rather than inflating the payload past `limit` bytes.
+pub fn decode(tag: u8, payload: Vec<u8>, limit: u64) -> io::Result<Vec<u8>> {
+    match tag {
+        RAW_TAG => Ok(payload),
+        DEFLATE_TAG => {
+            let mut out = vec![];
+            DeflateDecoder::new(&payload[..]).take(limit + 1).read_to_end(&mut out)?;
+            if out.len() as u64 > limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("inflates past {} bytes", limit),
+                ));
+            }
+            Ok(out)
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown codec tag {}", tag))),
+    }
+}
+
+/// Already compressed content (images, archives, media) barely shrinks on a quick pass over a
+/// sample, so skip the full compression for it.
+fn looks_incompressible(bytes: &[u8]) -> bool {
+    if bytes.len() <= SAMPLE_SIZE {
+        return false;
+    }
+
+    let sample = &bytes[..SAMPLE_SIZE];
+    match deflate(sample, Compression::fast()) {
+        Ok(compressed) => compressed.len() * 10 > sample.len() * 9,
+        Err(_) => true,
+    }
+}
+
+fn deflate(bytes: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![], level);
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u32 = 0x1234_5678;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deflate_round_trips_compressible_data() {
+        let bytes = b"bindrs ".iter().cloned().cycle().take(10_000).collect::<Vec<u8>>();
+        let (tag, payload) = Codec::Deflate.encode(&bytes);
+        assert_eq!(DEFLATE_TAG, tag);
+        assert!(payload.len() < bytes.len());
+        assert!(decode(tag, payload, bytes.len() as u64).unwrap() == bytes);
+    }
+
+    #[test]
+    fn inflating_past_the_limit_fails() {
+        let bytes = vec![0u8; 10_000];
+        let (tag, payload) = Codec::Deflate.encode(&bytes);
+        assert_eq!(DEFLATE_TAG, tag);
+        assert!(decode(tag, payload, 9_999).is_err());
+    }
+
+    #[test]
+    fn incompressible_data_is_sent_raw() {
+        let bytes = noise(10_000);
+        let (tag, payload) = Codec::Deflate.encode(&bytes);
+        assert_eq!(RAW_TAG, tag);
+        assert!(payload == bytes);
+    }
+
+    #[test]
+    fn none_codec_is_always_raw() {
+        let bytes = vec![0u8; 10_000];
+        let (tag, payload) = Codec::None.encode(&bytes);
+        assert_eq!(RAW_TAG, tag);
+        assert!(decode(tag, payload, bytes.len() as u64).unwrap() == bytes);
+    }
+}
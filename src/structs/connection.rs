@@ -0,0 +1,101 @@
+use error::BindrsError;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+// How long either end waits on the other while a connection is being set up
+pub const HANDSHAKE_TIMEOUT_SECS: u64 = 30;
+
+/// A socket a session runs over, which can be split into a reader and a writer and shut down
+/// from any of its handles.
+pub trait Connection: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Connection for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Another handle on the connection, for reading while the original writes or for shutting it
+/// down from elsewhere.
+pub fn split<C: Connection>(stream: &C) -> Result<C, BindrsError> {
+    stream.try_clone().map_err(|e| {
+        BindrsError::Remote(format!("Failed to split connection: {}", e))
+    })
+}
+
+/// Bounds reads while the connection is being set up, so a silent remote can't hold it forever,
+/// and lifts the bound once it's done.
+pub fn set_handshake_timeout<C: Connection>(stream: &C, handshaking: bool) -> Result<(), BindrsError> {
+    let timeout = if handshaking {
+        Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS))
+    } else {
+        None
+    };
+    stream.set_read_timeout(timeout).map_err(|e| {
+        BindrsError::Remote(format!("Failed to set connection timeout: {}", e))
+    })
+}
+
+/// Shuts down whatever connection was opened last, so threads left over from its session give
+/// up and a slave serving one master at a time is free again, then opens a new one with
+/// `connect` and keeps a handle on it for next time.
+pub fn replace<C, R, W, F>(last: &mut Option<C>, connect: F) -> Result<(R, W), BindrsError>
+where
+    C: Connection,
+    F: FnOnce() -> Result<(C, R, W), BindrsError>,
+{
+    if let Some(old) = last.take() {
+        old.shutdown(Shutdown::Both).unwrap_or_default();
+    }
+    let (stream, reader, writer) = connect()?;
+    *last = Some(stream);
+    Ok((reader, writer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replacing_a_connection_shuts_the_old_one_down() {
+        let (first, mut first_peer) = UnixStream::pair().unwrap();
+        let (second, _second_peer) = UnixStream::pair().unwrap();
+        let mut last = None;
+
+        replace(&mut last, || Ok((split(&first)?, (), ()))).unwrap();
+        replace(&mut last, || Ok((split(&second)?, (), ()))).unwrap();
+
+        // The old connection's peer sees it close
+        let mut buf = [0u8; 1];
+        assert_eq!(first_peer.read(&mut buf).unwrap(), 0);
+        assert!(last.is_some());
+    }
+}
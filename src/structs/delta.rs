@@ -0,0 +1,355 @@
+use error::BindrsError;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write, BufReader};
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const MIN_BLOCK_SIZE: u64 = 700;
+const MAX_BLOCK_SIZE: u64 = 128 * 1024;
+// Signatures of more blocks than this outgrow a batch, so bigger files are sent in full
+const MAX_SIGNATURE_BLOCKS: u64 = 128 * 1024;
+const CHAR_OFFSET: u32 = 31;
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Signatures {
+    pub block_size: u32,
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub enum DeltaOp {
+    Copy(u64),
+    Literal(Vec<u8>),
+    /// A run of zeros the sender found in a hole, sent as its length.
+    Hole(u64),
+}
+
+impl Signatures {
+    pub fn build_from_file(file: &File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        let block_size = block_size_for(len);
+        if len / block_size as u64 >= MAX_SIGNATURE_BLOCKS {
+            return Err(io::Error::new(io::ErrorKind::Other, "too large to diff"));
+        }
+        let mut reader = BufReader::new(file);
+        let mut blocks = vec![];
+        let mut buf = vec![0u8; block_size];
+
+        loop {
+            let read = read_block(&mut reader, &mut buf)?;
+            if read == 0 {
+                break;
+            }
+            blocks.push(BlockSignature {
+                weak: weak_checksum(&buf[..read]),
+                strong: strong_checksum(&buf[..read]),
+            });
+        }
+
+        Ok(Self {
+            block_size: block_size as u32,
+            blocks,
+        })
+    }
+}
+
+impl DeltaOp {
+    /// `block` is scratch space one block long, reused across every op for a file.
+    pub fn apply<B: Read + Seek, W: Write>(&self, block: &mut [u8], basis: &mut B, out: &mut W) -> io::Result<()> {
+        match *self {
+            DeltaOp::Copy(index) => {
+                let offset = index.checked_mul(block.len() as u64).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "copied block past the end of any file")
+                })?;
+                basis.seek(SeekFrom::Start(offset))?;
+                let read = read_block(basis, block)?;
+                out.write_all(&block[..read])
+            }
+            DeltaOp::Literal(ref bytes) => out.write_all(bytes),
+            DeltaOp::Hole(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "hole in delta")),
+        }
+    }
+}
+
+/// Checks a block size sent by the remote before a block that size is allocated.
+pub fn check_block_size(block_size: u32) -> Result<(), BindrsError> {
+    if block_size == 0 || u64::from(block_size) > MAX_BLOCK_SIZE {
+        return Err(BindrsError::Protocol(format!("Received a delta with a block size of {}", block_size)));
+    }
+    Ok(())
+}
+
+/// Streams `reader` as delta ops against `signatures`, buffering at most one chunk of literal data.
+/// Failing to read is a `BindrsError::File`, anything else comes from `emit`.
+pub fn build_delta<R: Read, F: FnMut(DeltaOp) -> Result<(), BindrsError>>(
+    signatures: &Signatures,
+    reader: &mut R,
+    mut emit: F,
+) -> Result<(), BindrsError> {
+    let block_size = signatures.block_size as usize;
+    let mut lookup: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in signatures.blocks.iter().enumerate() {
+        lookup.entry(block.weak).or_insert_with(Vec::new).push(i);
+    }
+
+    let mut buf: Vec<u8> = vec![];
+    let mut eof = false;
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut rolling: Option<Rolling> = None;
+
+    loop {
+        if !eof && buf.len() < pos + block_size + 1 {
+            // Drop everything already emitted before reading further
+            buf.drain(..literal_start);
+            pos -= literal_start;
+            literal_start = 0;
+            eof = fill_buffer(reader, &mut buf, pos + block_size + CHUNK_SIZE).map_err(|e| {
+                BindrsError::File(format!("Failed to read file for delta: {}", e))
+            })?;
+        }
+
+        if pos >= buf.len() {
+            break;
+        }
+
+        let end = (pos + block_size).min(buf.len());
+        let weak = match rolling {
+            Some(ref r) => r.digest(),
+            None => {
+                let r = Rolling::new(&buf[pos..end]);
+                let d = r.digest();
+                rolling = Some(r);
+                d
+            }
+        };
+
+        let matched = lookup.get(&weak).and_then(|candidates| {
+            let strong = strong_checksum(&buf[pos..end]);
+            candidates.iter().find(|&&i| signatures.blocks[i].strong == strong)
+        });
+
+        if let Some(&index) = matched {
+            if literal_start < pos {
+                emit(DeltaOp::Literal(buf[literal_start..pos].to_vec()))?;
+            }
+            emit(DeltaOp::Copy(index as u64))?;
+            pos = end;
+            literal_start = pos;
+            rolling = None;
+        } else {
+            if let Some(ref mut r) = rolling {
+                let next = if end < buf.len() { Some(buf[end]) } else { None };
+                r.roll(buf[pos], next);
+            }
+            pos += 1;
+
+            if pos - literal_start >= CHUNK_SIZE {
+                emit(DeltaOp::Literal(buf[literal_start..pos].to_vec()))?;
+                literal_start = pos;
+            }
+        }
+    }
+
+    if literal_start < buf.len() {
+        emit(DeltaOp::Literal(buf[literal_start..].to_vec()))?;
+    }
+    Ok(())
+}
+
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for &byte in data {
+            a = a.wrapping_add(u32::from(byte) + CHAR_OFFSET);
+            b = b.wrapping_add(a);
+        }
+        Self {
+            a,
+            b,
+            len: data.len() as u32,
+        }
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+
+    fn roll(&mut self, out: u8, next: Option<u8>) {
+        let out = u32::from(out) + CHAR_OFFSET;
+        self.a = self.a.wrapping_sub(out);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out));
+        self.len -= 1;
+        if let Some(next) = next {
+            self.a = self.a.wrapping_add(u32::from(next) + CHAR_OFFSET);
+            self.b = self.b.wrapping_add(self.a);
+            self.len += 1;
+        }
+    }
+}
+
+fn block_size_for(len: u64) -> usize {
+    let mut size = (len as f64).sqrt() as u64;
+    size = size - size % 8;
+    size.max(MIN_BLOCK_SIZE).min(MAX_BLOCK_SIZE) as usize
+}
+
+fn weak_checksum(data: &[u8]) -> u32 {
+    Rolling::new(data).digest()
+}
+
+fn strong_checksum(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+fn fill_buffer<R: Read>(reader: &mut R, buf: &mut Vec<u8>, target: usize) -> io::Result<bool> {
+    let start = buf.len();
+    buf.resize(target.max(start), 0);
+    let read = read_block(reader, &mut buf[start..])?;
+    buf.truncate(start + read);
+    Ok(buf.len() < target)
+}
+
+pub fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tempdir::TempDir;
+
+    fn signatures_for(data: &[u8], block_size: usize) -> Signatures {
+        Signatures {
+            block_size: block_size as u32,
+            blocks: data.chunks(block_size)
+                .map(|block| {
+                    BlockSignature {
+                        weak: weak_checksum(block),
+                        strong: strong_checksum(block),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn delta_ops(signatures: &Signatures, target: &[u8]) -> Vec<DeltaOp> {
+        let mut ops = vec![];
+        build_delta(signatures, &mut Cursor::new(target), |op| {
+            ops.push(op);
+            Ok(())
+        }).unwrap();
+        ops
+    }
+
+    fn apply_to_vec(ops: &[DeltaOp], block_size: usize, basis: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut basis = Cursor::new(basis);
+        let mut block = vec![0u8; block_size];
+        for op in ops {
+            op.apply(&mut block, &mut basis, &mut out).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn rolling_checksum_matches_fresh_checksum() {
+        let data: Vec<u8> = (0..64).map(|i| (i * 7) as u8).collect();
+        let mut rolling = Rolling::new(&data[0..16]);
+        for pos in 0..48 {
+            assert_eq!(weak_checksum(&data[pos..pos + 16]), rolling.digest());
+            rolling.roll(data[pos], Some(data[pos + 16]));
+        }
+    }
+
+    #[test]
+    fn delta_reuses_unchanged_blocks_after_insert() {
+        let basis: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        let mut target = basis.clone();
+        target.splice(1000..1000, b"inserted".iter().cloned());
+
+        let ops = delta_ops(&signatures_for(&basis, 128), &target);
+        let copies = ops.iter().filter(|op| match **op {
+            DeltaOp::Copy(_) => true,
+            DeltaOp::Literal(_) |
+            DeltaOp::Hole(_) => false,
+        });
+
+        assert!(copies.count() >= 28);
+        assert!(apply_to_vec(&ops, 128, &basis) == target);
+    }
+
+    #[test]
+    fn oversized_block_sizes_are_rejected() {
+        assert!(check_block_size(MIN_BLOCK_SIZE as u32).is_ok());
+        assert!(check_block_size(MAX_BLOCK_SIZE as u32).is_ok());
+        assert!(check_block_size(0).is_err());
+        assert!(check_block_size(MAX_BLOCK_SIZE as u32 + 1).is_err());
+        assert!(check_block_size(u32::max_value()).is_err());
+    }
+
+    #[test]
+    fn files_too_large_to_diff_have_no_signatures() {
+        let dir = TempDir::new("bindrs-delta").unwrap();
+        let file = File::create(dir.path().join("huge")).unwrap();
+        // Sparse, so it takes no space
+        file.set_len(MAX_SIGNATURE_BLOCKS * MAX_BLOCK_SIZE).unwrap();
+        assert!(Signatures::build_from_file(&file).is_err());
+    }
+
+    #[test]
+    fn copies_from_impossible_offsets_are_rejected() {
+        let mut block = vec![0u8; 128];
+        let mut out = vec![];
+        let result = DeltaOp::Copy(u64::max_value()).apply(&mut block, &mut Cursor::new(vec![0u8; 256]), &mut out);
+        assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn delta_against_unrelated_basis_is_literal() {
+        let basis = vec![0u8; 1024];
+        let target: Vec<u8> = (0..1000).map(|i| (i % 13 + 1) as u8).collect();
+
+        let ops = delta_ops(&signatures_for(&basis, 128), &target);
+        assert!(ops == vec![DeltaOp::Literal(target.clone())]);
+        assert!(apply_to_vec(&ops, 128, &basis) == target);
+    }
+
+    #[test]
+    fn delta_literals_are_bounded_by_chunk_size() {
+        let basis = vec![0u8; 256];
+        let target: Vec<u8> = (0..(CHUNK_SIZE * 3 + 17)).map(|i| (i % 13 + 1) as u8).collect();
+
+        let ops = delta_ops(&signatures_for(&basis, 128), &target);
+        assert_eq!(4, ops.len());
+        assert!(ops.iter().all(|op| match *op {
+            DeltaOp::Literal(ref bytes) => bytes.len() <= CHUNK_SIZE,
+            DeltaOp::Copy(_) |
+            DeltaOp::Hole(_) => false,
+        }));
+        assert!(apply_to_vec(&ops, 128, &basis) == target);
+    }
+}
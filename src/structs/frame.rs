@@ -0,0 +1,278 @@
+use bincode::{serialize, deserialize};
+use byteorder::{WriteBytesExt, ReadBytesExt, LittleEndian};
+use error::BindrsError;
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Write, BufReader, BufWriter, Read};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use structs::bound_file::{BoundFile, FileAction};
+use structs::codec::{self, Codec};
+use structs::delta::{DeltaOp, Signatures, CHUNK_SIZE};
+use structs::handshake::Handshake;
+use structs::manifest::Manifest;
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub enum Frame {
+    // Kept first so its encoding stays stable across protocol versions
+    Handshake(Handshake),
+    File(BoundFile),
+    Chunk(DeltaOp),
+    /// Closes a file's chunks with the SHA-256 of its full contents, holes hashed by length.
+    End(Vec<u8>),
+    SignatureRequest(String),
+    Signatures(String, Option<Signatures>),
+    /// Reports whether the `File` sent with the given sequence number was applied.
+    Ack(u64, Result<(), String>),
+    /// Sent while otherwise idle so the remote knows the connection is alive.
+    Heartbeat,
+    /// Part of what the slave holds, sent after the handshake when the session syncs it
+    /// initially. Parts follow one another up to the one marked last.
+    Manifest(Manifest, bool),
+    /// Part of the changes the master wants the slave to send after comparing manifests, sent in
+    /// reply to the manifest. Parts follow one another up to the one marked last.
+    Pull(Vec<(FileAction, String)>, bool),
+}
+
+/// Writes frames, numbering each one so the remote can acknowledge and order them. Frames are
+/// collected into batches that go out as a single length-prefixed, compressed unit, so a burst
+/// of small changes costs one write rather than one per frame.
+pub struct FrameWriter<W: Write> {
+    writer: BufWriter<W>,
+    codec: Codec,
+    next_seq: u64,
+    batch: Vec<u8>,
+}
+
+/// Reads frames, checking that none were lost or reordered on the way.
+pub struct FrameReader<R: Read> {
+    reader: BufReader<R>,
+    next_seq: u64,
+    last_received: Arc<Mutex<Instant>>,
+    batch: VecDeque<(u64, Frame)>,
+}
+
+// A batch is written out once it grows past this, even before a flush
+const BATCH_SIZE_BUDGET: usize = 1024 * 1024;
+// Batches are the budget plus one frame. Frames are a chunk or less, except file headers with
+// extended attributes and signatures of large files, which still fit in the rest.
+const MAX_BATCH_SIZE: u64 = (8 * BATCH_SIZE_BUDGET + CHUNK_SIZE) as u64;
+
+impl Frame {
+    fn encode(&self) -> Result<Vec<u8>, BindrsError> {
+        serialize(&self).map_err(|e| BindrsError::Internal(format!("Failed to encode frame: {}", e)))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, BindrsError> {
+        deserialize(bytes).map_err(|e| BindrsError::Protocol(format!("Failed to decode frame from remote: {}", e)))
+    }
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// Starts uncompressed so the handshake is readable before a codec is agreed.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            codec: Codec::None,
+            next_seq: 0,
+            batch: vec![],
+        }
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) -> Result<(), BindrsError> {
+        self.write_batch()?;
+        self.codec = codec;
+        Ok(())
+    }
+
+    /// The sequence number the next frame will be sent with.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Adds the frame to the current batch and returns the sequence number it was sent with.
+    pub fn send(&mut self, frame: &Frame) -> Result<u64, BindrsError> {
+        let seq = self.next_seq;
+        let encoded = frame.encode()?;
+        // Writes to a Vec can't fail
+        self.batch.write_u64::<LittleEndian>(seq).unwrap_or_default();
+        self.batch.write_u64::<LittleEndian>(encoded.len() as u64).unwrap_or_default();
+        self.batch.extend_from_slice(&encoded[..]);
+        self.next_seq += 1;
+
+        if self.batch.len() >= BATCH_SIZE_BUDGET {
+            self.write_batch()?;
+        }
+        Ok(seq)
+    }
+
+    pub fn flush(&mut self) -> Result<(), BindrsError> {
+        self.write_batch()?;
+        self.writer.flush().map_err(write_error)
+    }
+
+    fn write_batch(&mut self) -> Result<(), BindrsError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let (tag, payload) = self.codec.encode(&self.batch[..]);
+        let len = payload.len() as u64 + 1;
+        self.writer.write_u64::<LittleEndian>(len).map_err(write_error)?;
+        self.writer.write_u8(tag).map_err(write_error)?;
+        self.writer.write_all(&payload[..]).map_err(write_error)?;
+        self.batch.clear();
+        Ok(())
+    }
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            next_seq: 0,
+            last_received: Arc::new(Mutex::new(Instant::now())),
+            batch: VecDeque::new(),
+        }
+    }
+
+    /// When a frame last arrived, shared so it can be watched from another thread.
+    pub fn last_received(&self) -> Arc<Mutex<Instant>> {
+        self.last_received.clone()
+    }
+
+    /// Whether frames from the last batch are still waiting to be received.
+    pub fn has_buffered(&self) -> bool {
+        !self.batch.is_empty()
+    }
+
+    pub fn recv(&mut self) -> Result<(u64, Frame), BindrsError> {
+        while self.batch.is_empty() {
+            self.read_batch()?;
+        }
+
+        // Unwrap is safe - the batch was just checked to be non-empty
+        #[cfg_attr(feature="clippy", allow(option_unwrap_used))]
+        let (seq, frame) = self.batch.pop_front().unwrap();
+        if seq != self.next_seq {
+            return Err(BindrsError::Protocol(format!(
+                "Received frame {} from remote, expected frame {}",
+                seq,
+                self.next_seq
+            )));
+        }
+        self.next_seq += 1;
+        if let Ok(mut last_received) = self.last_received.lock() {
+            *last_received = Instant::now();
+        }
+        Ok((seq, frame))
+    }
+
+    fn read_batch(&mut self) -> Result<(), BindrsError> {
+        let len = self.reader.read_u64::<LittleEndian>().map_err(read_error)?;
+        if len == 0 {
+            return Err(BindrsError::Protocol("Received a batch without a codec tag".to_owned()));
+        }
+        if len - 1 > MAX_BATCH_SIZE {
+            return Err(BindrsError::Protocol(format!("Received a batch of {} bytes, over the limit", len - 1)));
+        }
+        let tag = self.reader.read_u8().map_err(read_error)?;
+
+        let mut vec: Vec<u8> = vec![];
+        (&mut self.reader).take(len - 1).read_to_end(&mut vec).map_err(read_error)?;
+        if vec.len() as u64 != len - 1 {
+            return Err(BindrsError::Disconnected("Remote closed the connection mid-batch".to_owned()));
+        }
+        let decompressed = codec::decode(tag, vec, MAX_BATCH_SIZE).map_err(|e| {
+            BindrsError::Protocol(format!("Failed to decompress batch from remote: {}", e))
+        })?;
+
+        let mut batch = Cursor::new(decompressed);
+        while (batch.position() as usize) < batch.get_ref().len() {
+            let seq = batch.read_u64::<LittleEndian>().map_err(truncated_error)?;
+            let frame_len = batch.read_u64::<LittleEndian>().map_err(truncated_error)?;
+            let mut encoded = vec![];
+            (&mut batch).take(frame_len).read_to_end(&mut encoded).map_err(truncated_error)?;
+            self.batch.push_back((seq, Frame::decode(&encoded[..])?));
+        }
+        Ok(())
+    }
+}
+
+fn write_error(e: io::Error) -> BindrsError {
+    BindrsError::Disconnected(format!("Couldn't write to remote: {}", e))
+}
+
+fn read_error(e: io::Error) -> BindrsError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        BindrsError::Disconnected("Remote closed the connection".to_owned())
+    } else {
+        BindrsError::Disconnected(format!("Couldn't read from remote: {}", e))
+    }
+}
+
+fn truncated_error(e: io::Error) -> BindrsError {
+    BindrsError::Protocol(format!("Received a truncated batch from remote: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_numbered_in_order() {
+        let mut writer = FrameWriter::new(vec![]);
+        writer.set_codec(Codec::Deflate).unwrap();
+        assert_eq!(0, writer.send(&Frame::SignatureRequest("a".to_owned())).unwrap());
+        assert_eq!(1, writer.send(&Frame::Ack(0, Err("failed".to_owned()))).unwrap());
+        writer.flush().unwrap();
+        let bytes = writer.writer.into_inner().unwrap();
+
+        let mut reader = FrameReader::new(&bytes[..]);
+        assert!(reader.recv().unwrap() == (0, Frame::SignatureRequest("a".to_owned())));
+        assert!(reader.recv().unwrap() == (1, Frame::Ack(0, Err("failed".to_owned()))));
+        match reader.recv() {
+            Err(BindrsError::Disconnected(_)) => (),
+            _ => panic!("expected the end of the stream to be a disconnect"),
+        }
+    }
+
+    #[test]
+    fn frames_are_held_until_flushed_or_over_budget() {
+        let mut writer = FrameWriter::new(vec![]);
+        writer.send(&Frame::Heartbeat).unwrap();
+        writer.send(&Frame::Heartbeat).unwrap();
+        assert!(writer.writer.get_ref().is_empty());
+        writer.flush().unwrap();
+        let flushed = writer.writer.get_ref().len();
+        assert!(flushed > 0);
+
+        writer.send(&Frame::Chunk(DeltaOp::Literal(vec![0; BATCH_SIZE_BUDGET]))).unwrap();
+        writer.writer.flush().unwrap();
+        assert!(writer.writer.get_ref().len() > flushed + BATCH_SIZE_BUDGET);
+    }
+
+    #[test]
+    fn batches_cut_short_are_a_disconnect() {
+        let mut writer = FrameWriter::new(vec![]);
+        writer.send(&Frame::SignatureRequest("a".to_owned())).unwrap();
+        writer.flush().unwrap();
+        let bytes = writer.writer.into_inner().unwrap();
+        match FrameReader::new(&bytes[..bytes.len() - 1]).recv() {
+            Err(BindrsError::Disconnected(_)) => (),
+            _ => panic!("expected a batch cut short to be a disconnect"),
+        }
+    }
+
+    #[test]
+    fn out_of_order_frames_are_rejected() {
+        let mut writer = FrameWriter::new(vec![]);
+        writer.next_seq = 1;
+        writer.send(&Frame::Heartbeat).unwrap();
+        writer.flush().unwrap();
+        let bytes = writer.writer.into_inner().unwrap();
+        match FrameReader::new(&bytes[..]).recv() {
+            Err(BindrsError::Protocol(ref msg)) if msg.contains("expected frame 0") => (),
+            _ => panic!("expected an out of order frame to be rejected"),
+        }
+    }
+}
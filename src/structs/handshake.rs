@@ -0,0 +1,301 @@
+use error::BindrsError;
+#[cfg(test)]
+use slog::Discard;
+use slog::Logger;
+use std::env::consts;
+use std::sync::Arc;
+use structs::codec::Codec;
+use structs::owner::OwnerMap;
+use structs::xattrs::XattrNamespaces;
+
+/// Bumped whenever the frame format changes in a way older peers can't read.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const DELTA_CAPABILITY: &str = "delta";
+pub const SYMLINK_CAPABILITY: &str = "symlinks";
+pub const ATIME_CAPABILITY: &str = "atimes";
+pub const XATTR_CAPABILITY: &str = "xattrs";
+pub const OWNER_CAPABILITY: &str = "owners";
+pub const HARD_LINK_CAPABILITY: &str = "hardlinks";
+pub const MANIFEST_CAPABILITY: &str = "manifest";
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+    pub capabilities: Vec<String>,
+    // How long either side may go without hearing from the other before giving up
+    pub timeout_secs: u64,
+}
+
+/// Features agreed for a single master/slave connection.
+#[derive(Clone)]
+pub struct Session {
+    pub codec: Codec,
+    pub delta: bool,
+    pub preserve_symlinks: bool,
+    pub preserve_atimes: bool,
+    pub xattr_namespaces: XattrNamespaces,
+    pub preserve_owners: bool,
+    // Only the master maps owners, translating them on the way out and back in
+    pub owner_map: Arc<OwnerMap>,
+    pub preserve_hard_links: bool,
+    // Set where rsync can't reach the remote, so the slave's manifest is used to mirror onto it
+    pub initial_sync: bool,
+    pub timeout_secs: u64,
+}
+
+impl Handshake {
+    pub fn local() -> Self {
+        Self::with_capabilities(
+            vec![
+                DELTA_CAPABILITY.to_owned(),
+                SYMLINK_CAPABILITY.to_owned(),
+                ATIME_CAPABILITY.to_owned(),
+                XATTR_CAPABILITY.to_owned(),
+                OWNER_CAPABILITY.to_owned(),
+                HARD_LINK_CAPABILITY.to_owned(),
+                MANIFEST_CAPABILITY.to_owned(),
+                Codec::Deflate.name().to_owned(),
+            ],
+            DEFAULT_TIMEOUT_SECS,
+        )
+    }
+
+    pub fn for_session(session: &Session) -> Self {
+        let mut capabilities = vec![session.codec.name().to_owned()];
+        if session.delta {
+            capabilities.push(DELTA_CAPABILITY.to_owned());
+        }
+        if session.preserve_symlinks {
+            capabilities.push(SYMLINK_CAPABILITY.to_owned());
+        }
+        if session.preserve_atimes {
+            capabilities.push(ATIME_CAPABILITY.to_owned());
+        }
+        if session.preserve_owners {
+            capabilities.push(OWNER_CAPABILITY.to_owned());
+        }
+        if session.preserve_hard_links {
+            capabilities.push(HARD_LINK_CAPABILITY.to_owned());
+        }
+        if session.initial_sync {
+            capabilities.push(MANIFEST_CAPABILITY.to_owned());
+        }
+        for namespace in session.xattr_namespaces.names() {
+            capabilities.push(xattr_capability(namespace));
+        }
+        Self::with_capabilities(capabilities, session.timeout_secs)
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "BindRS {} (protocol {}) on {}/{}",
+            self.version,
+            self.protocol_version,
+            self.os,
+            self.arch
+        )
+    }
+
+    fn with_capabilities(capabilities: Vec<String>, timeout_secs: u64) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            version: ::VERSION.to_owned(),
+            os: consts::OS.to_owned(),
+            arch: consts::ARCH.to_owned(),
+            capabilities,
+            timeout_secs,
+        }
+    }
+}
+
+impl Session {
+    /// Narrows the requested features down to those the remote supports, or explains why the
+    /// remote can't be used.
+    pub fn negotiate(log: &Logger, requested: &Self, remote: &Handshake) -> Result<Self, BindrsError> {
+        if remote.protocol_version != PROTOCOL_VERSION {
+            return Err(BindrsError::Protocol(format!(
+                "Remote BindRS speaks protocol {} but local speaks protocol {}. \
+                 Please update older version to match newer version. Local: {} - Remote: {}",
+                remote.protocol_version,
+                PROTOCOL_VERSION,
+                ::VERSION,
+                remote.version
+            )));
+        }
+
+        let codec = if requested.codec == Codec::None || remote.supports(requested.codec.name()) {
+            requested.codec
+        } else {
+            warn!(
+                log,
+                "Remote does not support {} compression, sending uncompressed",
+                requested.codec.name()
+            );
+            Codec::None
+        };
+
+        let preserve_symlinks = requested.preserve_symlinks && remote.supports(SYMLINK_CAPABILITY);
+        if requested.preserve_symlinks && !preserve_symlinks {
+            warn!(log, "Remote does not support symlinks, following them instead");
+        }
+
+        let preserve_atimes = requested.preserve_atimes && remote.supports(ATIME_CAPABILITY);
+        if requested.preserve_atimes && !preserve_atimes {
+            warn!(log, "Remote does not support access times, only preserving modification times");
+        }
+
+        let xattr_namespaces = if remote.supports(XATTR_CAPABILITY) {
+            requested.xattr_namespaces
+        } else {
+            if !requested.xattr_namespaces.is_empty() {
+                warn!(log, "Remote does not support extended attributes, skipping them");
+            }
+            XattrNamespaces::default()
+        };
+
+        let preserve_owners = requested.preserve_owners && remote.supports(OWNER_CAPABILITY);
+        if requested.preserve_owners && !preserve_owners {
+            warn!(log, "Remote does not support syncing owners, skipping them");
+        }
+
+        let preserve_hard_links = requested.preserve_hard_links && remote.supports(HARD_LINK_CAPABILITY);
+        if requested.preserve_hard_links && !preserve_hard_links {
+            warn!(log, "Remote does not support hard links, sending linked files as copies");
+        }
+
+        if requested.initial_sync && !remote.supports(MANIFEST_CAPABILITY) {
+            return Err(BindrsError::Remote(format!(
+                "Remote BindRS can't sync without rsync. Please update it to match local: {} - Remote: {}",
+                ::VERSION,
+                remote.version
+            )));
+        }
+
+        Ok(Self {
+            codec,
+            delta: requested.delta && remote.supports(DELTA_CAPABILITY),
+            preserve_symlinks,
+            preserve_atimes,
+            xattr_namespaces,
+            preserve_owners,
+            owner_map: requested.owner_map.clone(),
+            preserve_hard_links,
+            initial_sync: requested.initial_sync,
+            timeout_secs: requested.timeout_secs,
+        })
+    }
+
+    /// Reads the features chosen by the master out of its handshake.
+    pub fn from_handshake(master: &Handshake) -> Self {
+        Self {
+            codec: if master.supports(Codec::Deflate.name()) {
+                Codec::Deflate
+            } else {
+                Codec::None
+            },
+            delta: master.supports(DELTA_CAPABILITY),
+            preserve_symlinks: master.supports(SYMLINK_CAPABILITY),
+            preserve_atimes: master.supports(ATIME_CAPABILITY),
+            xattr_namespaces: XattrNamespaces::all().filter(|namespace| master.supports(&xattr_capability(namespace))),
+            preserve_owners: master.supports(OWNER_CAPABILITY),
+            owner_map: Arc::new(OwnerMap::default()),
+            preserve_hard_links: master.supports(HARD_LINK_CAPABILITY),
+            initial_sync: master.supports(MANIFEST_CAPABILITY),
+            timeout_secs: master.timeout_secs,
+        }
+    }
+}
+
+fn xattr_capability(namespace: &str) -> String {
+    format!("{}.{}", XATTR_CAPABILITY, namespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_logger() -> Logger {
+        Logger::root(Discard, o!())
+    }
+
+    fn full_session() -> Session {
+        Session {
+            codec: Codec::Deflate,
+            delta: true,
+            preserve_symlinks: true,
+            preserve_atimes: true,
+            xattr_namespaces: XattrNamespaces::from_names(vec!["user", "system"]).unwrap(),
+            preserve_owners: true,
+            owner_map: Arc::new(OwnerMap::default()),
+            preserve_hard_links: true,
+            initial_sync: false,
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+        }
+    }
+
+    #[test]
+    fn negotiate_uses_common_features() {
+        let session = Session::negotiate(&test_logger(), &full_session(), &Handshake::local()).unwrap();
+        assert!(session.codec == Codec::Deflate);
+        assert!(session.delta);
+        assert!(session.preserve_symlinks);
+        assert!(session.preserve_atimes);
+        assert_eq!(vec!["user", "system"], session.xattr_namespaces.names());
+        assert!(session.preserve_owners);
+    }
+
+    #[test]
+    fn negotiate_falls_back_when_remote_lacks_codec() {
+        let mut remote = Handshake::local();
+        remote.capabilities = vec![];
+        let session = Session::negotiate(&test_logger(), &full_session(), &remote).unwrap();
+        assert!(session.codec == Codec::None);
+        assert!(!session.delta);
+        assert!(!session.preserve_symlinks);
+        assert!(!session.preserve_atimes);
+        assert!(session.xattr_namespaces.is_empty());
+        assert!(!session.preserve_owners);
+        assert!(!session.preserve_hard_links);
+    }
+
+    #[test]
+    fn negotiate_refuses_other_protocol_versions() {
+        let mut remote = Handshake::local();
+        remote.protocol_version = PROTOCOL_VERSION + 1;
+        assert!(Session::negotiate(&test_logger(), &full_session(), &remote).is_err());
+    }
+
+    #[test]
+    fn session_survives_round_trip_through_handshake() {
+        let session = Session {
+            codec: Codec::None,
+            delta: true,
+            preserve_symlinks: false,
+            preserve_atimes: true,
+            xattr_namespaces: XattrNamespaces::from_names(vec!["security"]).unwrap(),
+            preserve_owners: false,
+            owner_map: Arc::new(OwnerMap::default()),
+            preserve_hard_links: true,
+            initial_sync: true,
+            timeout_secs: 5,
+        };
+        let slave_session = Session::from_handshake(&Handshake::for_session(&session));
+        assert!(slave_session.codec == Codec::None);
+        assert!(slave_session.delta);
+        assert!(!slave_session.preserve_symlinks);
+        assert!(slave_session.preserve_atimes);
+        assert_eq!(vec!["security"], slave_session.xattr_namespaces.names());
+        assert!(!slave_session.preserve_owners);
+        assert!(slave_session.preserve_hard_links);
+        assert!(slave_session.initial_sync);
+        assert_eq!(5, slave_session.timeout_secs);
+    }
+}
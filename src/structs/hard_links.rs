@@ -0,0 +1,151 @@
+use regex::RegexSet;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use structs::bound_file::FileAction;
+use structs::watcher::walk_children;
+
+/// Groups the paths in the bound tree that share an inode, so a new link can be sent as a link
+/// rather than a copy, and a change to one path can be followed by relinking the others.
+#[derive(Default, Debug)]
+pub struct HardLinks {
+    groups: HashMap<(u64, u64), Vec<String>>,
+}
+
+impl HardLinks {
+    /// Finds every file under `base_dir` that is linked more than once.
+    pub fn scan(base_dir: &str, ignores: &RegexSet) -> Self {
+        let mut links = Self::default();
+        walk_children(base_dir, "", ignores, &mut |action, path| if action == FileAction::CreateUpdate {
+            links.record(base_dir, &path);
+        });
+        links
+    }
+
+    /// Records whatever is at `path` now, returning the first known path it was just linked to.
+    /// A path already known to share the inode returns nothing, as it is a change rather than a
+    /// new link.
+    pub fn new_link_to(&mut self, base_dir: &str, path: &str) -> Option<String> {
+        let key = match inode_key(base_dir, path) {
+            Some(key) => key,
+            None => {
+                self.forget(path);
+                return None;
+            }
+        };
+
+        let target = {
+            let group = self.pruned_group(base_dir, key);
+            if group.iter().any(|member| member == path) {
+                return None;
+            }
+            group.first().cloned()
+        };
+        self.record(base_dir, path);
+        target
+    }
+
+    /// Records whatever is at `path` now, returning the other paths sharing its inode.
+    pub fn others(&mut self, base_dir: &str, path: &str) -> Vec<String> {
+        self.record(base_dir, path);
+        match inode_key(base_dir, path) {
+            Some(key) => {
+                self.pruned_group(base_dir, key)
+                    .iter()
+                    .filter(|member| *member != path)
+                    .cloned()
+                    .collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Keeps track of a change that has just been made to the tree.
+    pub fn applied(&mut self, base_dir: &str, action: &FileAction, path: &str) {
+        match *action {
+            FileAction::CreateUpdate => self.record(base_dir, path),
+            FileAction::HardLink { ref target } => {
+                self.record(base_dir, target);
+                self.record(base_dir, path);
+            }
+            FileAction::Rename { ref from } => self.rename(from, path),
+            FileAction::Delete |
+            FileAction::DeleteDir => self.forget(path),
+            _ => (),
+        }
+    }
+
+    fn record(&mut self, base_dir: &str, path: &str) {
+        self.forget(path);
+        if let Some(key) = inode_key(base_dir, path) {
+            self.groups.entry(key).or_insert_with(Vec::new).push(path.to_owned());
+        }
+    }
+
+    /// Drops `path`, along with everything below it.
+    fn forget(&mut self, path: &str) {
+        let child_prefix = format!("{}/", path);
+        for group in self.groups.values_mut() {
+            group.retain(|member| member != path && !member.starts_with(&child_prefix));
+        }
+        self.groups.retain(|_, group| !group.is_empty());
+    }
+
+    fn rename(&mut self, from: &str, to: &str) {
+        let child_prefix = format!("{}/", from);
+        for group in self.groups.values_mut() {
+            for member in group.iter_mut() {
+                if member == from {
+                    *member = to.to_owned();
+                } else if member.starts_with(&child_prefix) {
+                    *member = format!("{}/{}", to, &member[child_prefix.len()..]);
+                }
+            }
+        }
+    }
+
+    /// The paths recorded for `key` that still have that inode.
+    fn pruned_group(&mut self, base_dir: &str, key: (u64, u64)) -> &[String] {
+        let group = self.groups.entry(key).or_insert_with(Vec::new);
+        group.retain(|member| inode_key(base_dir, member) == Some(key));
+        group
+    }
+}
+
+/// Device and inode of a regular file linked more than once, without following symlinks.
+fn inode_key(base_dir: &str, path: &str) -> Option<(u64, u64)> {
+    match fs::symlink_metadata(format!("{}/{}", base_dir, path)) {
+        Ok(ref metadata) if metadata.is_file() && metadata.nlink() > 1 => Some((metadata.dev(), metadata.ino())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn links_are_told_apart_from_changes() {
+        let dir = TempDir::new("bindrs-hard-links").unwrap();
+        let base_dir = dir.path().to_str().unwrap();
+        fs::write(format!("{}/a", base_dir), "contents").unwrap();
+        fs::hard_link(format!("{}/a", base_dir), format!("{}/b", base_dir)).unwrap();
+
+        let mut links = HardLinks::scan(base_dir, &RegexSet::new(Vec::<&str>::new()).unwrap());
+        assert_eq!(None, links.new_link_to(base_dir, "a"));
+        assert_eq!(vec!["b".to_owned()], links.others(base_dir, "a"));
+
+        fs::hard_link(format!("{}/a", base_dir), format!("{}/c", base_dir)).unwrap();
+        assert!(links.new_link_to(base_dir, "c").is_some());
+        assert_eq!(None, links.new_link_to(base_dir, "c"));
+
+        fs::rename(format!("{}/c", base_dir), format!("{}/d", base_dir)).unwrap();
+        links.applied(base_dir, &FileAction::Rename { from: "c".to_owned() }, "d");
+        assert_eq!(None, links.new_link_to(base_dir, "d"));
+
+        fs::remove_file(format!("{}/b", base_dir)).unwrap();
+        links.applied(base_dir, &FileAction::Delete, "b");
+        assert_eq!(vec!["a".to_owned()], links.others(base_dir, "d"));
+    }
+}
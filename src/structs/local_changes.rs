@@ -0,0 +1,97 @@
+use error::BindrsError;
+use helpers;
+use regex::RegexSet;
+use std::collections::HashSet;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use structs::bound_file::FileAction;
+use structs::watcher::BindrsWatcher;
+
+/// Watches the local directory across sessions. Changes go to whichever session is attached,
+/// and every changed path is remembered so a session reconnecting after a drop knows what
+/// changed here in the meantime.
+pub struct LocalChanges {
+    // Watching stops once this is dropped
+    _watcher: BindrsWatcher,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    session: Option<Sender<(FileAction, String)>>,
+    dirty: HashSet<String>,
+}
+
+/// Lets a session forget changed paths once the remote has them too.
+#[derive(Clone)]
+pub struct DirtyPaths(Arc<Mutex<State>>);
+
+impl LocalChanges {
+    pub fn watch(base_dir: &str, ignores: &RegexSet) -> Result<Self, BindrsError> {
+        let mut watcher = BindrsWatcher::new(base_dir, ignores);
+        watcher.watch()?;
+        let rx = watcher.rx.take().ok_or_else(|| {
+            BindrsError::Watch("Couldn't get local receive channel off local watcher".to_owned())
+        })?;
+
+        let state = Arc::new(Mutex::new(State::default()));
+        let state_clone = state.clone();
+        thread::spawn(move || {
+            for (action, path) in rx {
+                let mut state = match helpers::lock(&state_clone, "local changes") {
+                    Ok(state) => state,
+                    Err(_) => return,
+                };
+                if let FileAction::Rename { ref from } = action {
+                    state.dirty.insert(from.clone());
+                }
+                state.dirty.insert(path.clone());
+                let sent = match state.session {
+                    Some(ref session) => session.send((action, path)).is_ok(),
+                    None => true,
+                };
+                if !sent {
+                    state.session = None;
+                }
+            }
+
+            // The attached session finds out watching stopped once its channel closes
+            if let Ok(mut state) = helpers::lock(&state_clone, "local changes") {
+                state.session = None;
+            }
+        });
+
+        Ok(Self {
+            _watcher: watcher,
+            state,
+        })
+    }
+
+    /// Sends changes from now on to a new session, instead of any earlier one.
+    pub fn attach(&self) -> Result<(Receiver<(FileAction, String)>, DirtyPaths), BindrsError> {
+        let (tx, rx) = channel();
+        helpers::lock(&self.state, "local changes")?.session = Some(tx);
+        Ok((rx, DirtyPaths(self.state.clone())))
+    }
+
+    /// Remembers `paths` as changed here, such as those a session still has to send.
+    pub fn mark_dirty<I: IntoIterator<Item = String>>(&self, paths: I) -> Result<(), BindrsError> {
+        helpers::lock(&self.state, "local changes")?.dirty.extend(paths);
+        Ok(())
+    }
+
+    /// Every path changed since this was last called.
+    pub fn take_dirty(&self) -> Result<HashSet<String>, BindrsError> {
+        let mut state = helpers::lock(&self.state, "local changes")?;
+        Ok(mem::replace(&mut state.dirty, HashSet::new()))
+    }
+}
+
+impl DirtyPaths {
+    pub fn synced(&self, path: &str) -> Result<(), BindrsError> {
+        helpers::lock(&self.0, "local changes")?.dirty.remove(path);
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use error::BindrsError;
+use filetime::FileTime;
+use regex::RegexSet;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::mem;
+use structs::bound_file::{FileAction, Timestamp};
+use structs::frame::{Frame, FrameReader, FrameWriter};
+use structs::watcher::walk_children;
+
+// Manifests and pulls go out in parts of about this many bytes, so no one frame outgrows a batch
+const PART_SIZE: usize = 256 * 1024;
+// Roughly what an entry costs besides its paths
+const ENTRY_OVERHEAD: usize = 32;
+
+/// What a synced directory holds, so a remote can be brought in line with it without rsync.
+#[derive(Serialize, Deserialize, PartialEq, Default, Debug)]
+pub struct Manifest {
+    entries: BTreeMap<String, Entry>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+enum Entry {
+    File { len: u64, mtime: Timestamp },
+    Dir,
+    Symlink(String),
+}
+
+impl Manifest {
+    /// Lists everything under `base_dir` that isn't ignored, without following symlinks.
+    pub fn scan(base_dir: &str, ignores: &RegexSet) -> Self {
+        let mut entries = BTreeMap::new();
+        walk_children(base_dir, "", ignores, &mut |_, path| {
+            let full_str_path = format!("{}/{}", base_dir, path);
+            let metadata = match fs::symlink_metadata(&full_str_path) {
+                Ok(metadata) => metadata,
+                Err(_) => return,
+            };
+            let entry = if metadata.file_type().is_symlink() {
+                match fs::read_link(&full_str_path) {
+                    Ok(target) => Entry::Symlink(target.to_string_lossy().into_owned()),
+                    Err(_) => return,
+                }
+            } else if metadata.is_dir() {
+                Entry::Dir
+            } else {
+                Entry::File {
+                    len: metadata.len(),
+                    mtime: Timestamp::from(FileTime::from_last_modification_time(&metadata)),
+                }
+            };
+            entries.insert(path, entry);
+        });
+        Self { entries }
+    }
+
+    /// Sends the manifest as `Frame::Manifest` parts.
+    pub fn send<W: Write>(self, writer: &mut FrameWriter<W>) -> Result<(), BindrsError> {
+        let entries = self.entries.into_iter().collect();
+        let size = |&(ref path, ref entry): &(String, Entry)| match *entry {
+            Entry::Symlink(ref target) => path.len() + target.len(),
+            _ => path.len(),
+        };
+        send_parts(writer, entries, size, |part, last| {
+            Frame::Manifest(Self { entries: part.into_iter().collect() }, last)
+        })
+    }
+
+    /// Receives a manifest sent with `send`.
+    pub fn recv<R: Read>(reader: &mut FrameReader<R>) -> Result<Self, BindrsError> {
+        let mut entries = BTreeMap::new();
+        loop {
+            match reader.recv()? {
+                (_, Frame::Manifest(part, last)) => {
+                    entries.extend(part.entries);
+                    if last {
+                        return Ok(Self { entries });
+                    }
+                }
+                _ => {
+                    return Err(BindrsError::Protocol(
+                        "Remote did not send its manifest".to_owned(),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// The changes that make `remote` match this tree: whatever is missing or differs there is
+    /// sent, and whatever only exists there is deleted. Files are compared by length and
+    /// modification time.
+    pub fn changes_to_mirror(&self, remote: &Self) -> Vec<(FileAction, String)> {
+        let mut changes = vec![];
+        let mut deleted_dirs: Vec<&str> = vec![];
+        for (path, entry) in &remote.entries {
+            if self.entries.contains_key(path) ||
+                deleted_dirs.iter().any(|dir| path.starts_with(&format!("{}/", dir)))
+            {
+                continue;
+            }
+            if *entry == Entry::Dir {
+                deleted_dirs.push(path);
+                changes.push((FileAction::DeleteDir, path.clone()));
+            } else {
+                changes.push((FileAction::Delete, path.clone()));
+            }
+        }
+
+        for (path, entry) in &self.entries {
+            if remote.entries.get(path) == Some(entry) {
+                continue;
+            }
+            let action = match *entry {
+                Entry::Dir => FileAction::CreateDir,
+                Entry::File { .. } |
+                Entry::Symlink(_) => FileAction::CreateUpdate,
+            };
+            changes.push((action, path.clone()));
+        }
+        changes
+    }
+
+    /// Whether `remote` is missing most of what's here, as when it was wiped or remounted empty
+    /// rather than edited.
+    pub fn looks_wiped(&self, remote: &Self) -> bool {
+        let missing = self.entries.keys().filter(|path| !remote.entries.contains_key(*path)).count();
+        missing > 0 && missing * 2 > self.entries.len()
+    }
+
+    /// Splits the differences from `remote` into the changes to send and the changes the remote
+    /// should send back, for catching up after a disconnect. Where both sides hold a file the
+    /// newer one wins. Otherwise this side wins for paths in `dirty`, which changed here since
+    /// the two were last in sync, and for anything around them, so nothing changed here is
+    /// deleted. Every other difference must have been made on the remote, except when the remote
+    /// looks wiped, where nothing it's missing is deleted here.
+    pub fn reconcile(
+        &self,
+        remote: &Self,
+        dirty: &HashSet<String>,
+    ) -> (Vec<(FileAction, String)>, Vec<(FileAction, String)>) {
+        let wiped = self.looks_wiped(remote);
+        let local_wins = |path: &str| match (self.entries.get(path), remote.entries.get(path)) {
+            (Some(&Entry::File { mtime: ref local, .. }), Some(&Entry::File { mtime: ref remote, .. })) => {
+                local >= remote
+            }
+            (Some(_), None) if wiped => true,
+            _ => {
+                dirty.iter().any(|dirty| {
+                    dirty == path || dirty.starts_with(&format!("{}/", path)) ||
+                        path.starts_with(&format!("{}/", dirty))
+                })
+            }
+        };
+
+        let send = self.changes_to_mirror(remote)
+            .into_iter()
+            .filter(|&(_, ref path)| local_wins(path))
+            .collect();
+        let pull = remote
+            .changes_to_mirror(self)
+            .into_iter()
+            .filter(|&(_, ref path)| !local_wins(path))
+            .collect();
+        (send, pull)
+    }
+}
+
+/// Sends the changes the master wants from the slave as `Frame::Pull` parts.
+pub fn send_pull<W: Write>(changes: Vec<(FileAction, String)>, writer: &mut FrameWriter<W>) -> Result<(), BindrsError> {
+    let size = |&(ref action, ref path): &(FileAction, String)| match *action {
+        FileAction::Rename { from: ref other } |
+        FileAction::HardLink { target: ref other } => path.len() + other.len(),
+        _ => path.len(),
+    };
+    send_parts(writer, changes, size, Frame::Pull)
+}
+
+/// Receives the changes sent with `send_pull`.
+pub fn recv_pull<R: Read>(reader: &mut FrameReader<R>) -> Result<Vec<(FileAction, String)>, BindrsError> {
+    let mut changes = vec![];
+    loop {
+        match reader.recv()? {
+            (_, Frame::Pull(part, last)) => {
+                changes.extend(part);
+                if last {
+                    return Ok(changes);
+                }
+            }
+            _ => {
+                return Err(BindrsError::Protocol(
+                    "Master did not reply to the manifest".to_owned(),
+                ))
+            }
+        }
+    }
+}
+
+fn send_parts<T, W, S, F>(writer: &mut FrameWriter<W>, items: Vec<T>, size: S, frame: F) -> Result<(), BindrsError>
+where
+    W: Write,
+    S: Fn(&T) -> usize,
+    F: Fn(Vec<T>, bool) -> Frame,
+{
+    let mut part = vec![];
+    let mut part_size = 0;
+    for item in items {
+        part_size += size(&item) + ENTRY_OVERHEAD;
+        part.push(item);
+        if part_size >= PART_SIZE {
+            writer.send(&frame(mem::replace(&mut part, vec![]), false))?;
+            part_size = 0;
+        }
+    }
+    writer.send(&frame(part, true))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime;
+    use std::os::unix::fs as unix_fs;
+    use structs::pipe::pipe;
+    use tempdir::TempDir;
+
+    #[test]
+    fn mirroring_sends_differences_and_deletes_extras() {
+        let local_dir = TempDir::new("bindrs-manifest-local").unwrap();
+        let remote_dir = TempDir::new("bindrs-manifest-remote").unwrap();
+        let local = local_dir.path().to_str().unwrap();
+        let remote = remote_dir.path().to_str().unwrap();
+        let ignores = RegexSet::new(Vec::<&str>::new()).unwrap();
+
+        for base_dir in &[local, remote] {
+            fs::create_dir(format!("{}/same", base_dir)).unwrap();
+            fs::write(format!("{}/same/file", base_dir), "same").unwrap();
+        }
+        let mtime = FileTime::from_unix_time(1_500_000_000, 0);
+        filetime::set_file_mtime(format!("{}/same/file", local), mtime).unwrap();
+        filetime::set_file_mtime(format!("{}/same/file", remote), mtime).unwrap();
+        fs::write(format!("{}/changed", local), "new").unwrap();
+        fs::write(format!("{}/changed", remote), "older").unwrap();
+        unix_fs::symlink("same/file", format!("{}/link", local)).unwrap();
+        fs::create_dir_all(format!("{}/extra/nested", remote)).unwrap();
+        fs::write(format!("{}/extra/nested/file", remote), "gone").unwrap();
+
+        let changes = Manifest::scan(local, &ignores).changes_to_mirror(&Manifest::scan(remote, &ignores));
+        assert!(
+            changes ==
+                vec![
+                    (FileAction::DeleteDir, "extra".to_owned()),
+                    (FileAction::CreateUpdate, "changed".to_owned()),
+                    (FileAction::CreateUpdate, "link".to_owned()),
+                ]
+        );
+    }
+
+    #[test]
+    fn reconciling_keeps_changes_from_both_sides() {
+        let local_dir = TempDir::new("bindrs-manifest-local").unwrap();
+        let remote_dir = TempDir::new("bindrs-manifest-remote").unwrap();
+        let local = local_dir.path().to_str().unwrap();
+        let remote = remote_dir.path().to_str().unwrap();
+        let ignores = RegexSet::new(Vec::<&str>::new()).unwrap();
+
+        let older = FileTime::from_unix_time(1_500_000_000, 0);
+        let newer = FileTime::from_unix_time(1_600_000_000, 0);
+        fs::write(format!("{}/edited_here", local), "new").unwrap();
+        fs::write(format!("{}/edited_here", remote), "old").unwrap();
+        filetime::set_file_mtime(format!("{}/edited_here", local), newer).unwrap();
+        filetime::set_file_mtime(format!("{}/edited_here", remote), older).unwrap();
+        fs::write(format!("{}/edited_there", local), "old").unwrap();
+        fs::write(format!("{}/edited_there", remote), "new").unwrap();
+        filetime::set_file_mtime(format!("{}/edited_there", local), older).unwrap();
+        filetime::set_file_mtime(format!("{}/edited_there", remote), newer).unwrap();
+
+        fs::write(format!("{}/created_here", local), "").unwrap();
+        fs::write(format!("{}/deleted_there", local), "").unwrap();
+        fs::create_dir(format!("{}/created_there", remote)).unwrap();
+        fs::write(format!("{}/created_there/file", remote), "").unwrap();
+        fs::write(format!("{}/deleted_here", remote), "").unwrap();
+
+        let dirty: HashSet<String> = vec!["created_here".to_owned(), "deleted_here".to_owned()]
+            .into_iter()
+            .collect();
+        let (send, pull) = Manifest::scan(local, &ignores).reconcile(&Manifest::scan(remote, &ignores), &dirty);
+        assert!(
+            send ==
+                vec![
+                    (FileAction::Delete, "deleted_here".to_owned()),
+                    (FileAction::CreateUpdate, "created_here".to_owned()),
+                    (FileAction::CreateUpdate, "edited_here".to_owned()),
+                ]
+        );
+        assert!(
+            pull ==
+                vec![
+                    (FileAction::Delete, "deleted_there".to_owned()),
+                    (FileAction::CreateDir, "created_there".to_owned()),
+                    (FileAction::CreateUpdate, "created_there/file".to_owned()),
+                    (FileAction::CreateUpdate, "edited_there".to_owned()),
+                ]
+        );
+    }
+    #[test]
+    fn reconciling_with_a_wiped_remote_deletes_nothing_here() {
+        let local_dir = TempDir::new("bindrs-manifest-local").unwrap();
+        let remote_dir = TempDir::new("bindrs-manifest-remote").unwrap();
+        let local = local_dir.path().to_str().unwrap();
+        let remote = remote_dir.path().to_str().unwrap();
+        let ignores = RegexSet::new(Vec::<&str>::new()).unwrap();
+
+        fs::create_dir(format!("{}/dir", local)).unwrap();
+        fs::write(format!("{}/dir/file", local), "kept").unwrap();
+        fs::write(format!("{}/file", local), "kept").unwrap();
+
+        let (local, remote) = (Manifest::scan(local, &ignores), Manifest::scan(remote, &ignores));
+        assert!(local.looks_wiped(&remote));
+        assert!(!local.looks_wiped(&local));
+        let (send, pull) = local.reconcile(&remote, &HashSet::new());
+        assert!(pull.is_empty());
+        assert!(
+            send ==
+                vec![
+                    (FileAction::CreateDir, "dir".to_owned()),
+                    (FileAction::CreateUpdate, "dir/file".to_owned()),
+                    (FileAction::CreateUpdate, "file".to_owned()),
+                ]
+        );
+    }
+
+    #[test]
+    fn large_manifests_and_pulls_go_in_parts() {
+        let mut manifest = Manifest::default();
+        let mut changes = vec![];
+        for i in 0..20_000 {
+            let path = format!("some/deeply/nested/directory/file-{}", i);
+            manifest.entries.insert(path.clone(), Entry::Dir);
+            changes.push((FileAction::CreateUpdate, path));
+        }
+        let expected_changes = changes.clone();
+
+        let (reader, writer) = pipe();
+        let mut writer = FrameWriter::new(writer);
+        Manifest::default().send(&mut writer).unwrap();
+        assert_eq!(1, writer.next_seq());
+        manifest.send(&mut writer).unwrap();
+        send_pull(changes, &mut writer).unwrap();
+        assert!(writer.next_seq() > 5);
+        writer.flush().unwrap();
+
+        let mut reader = FrameReader::new(reader);
+        assert_eq!(Manifest::default(), Manifest::recv(&mut reader).unwrap());
+        assert_eq!(20_000, Manifest::recv(&mut reader).unwrap().entries.len());
+        assert!(recv_pull(&mut reader).unwrap() == expected_changes);
+    }
+}
@@ -1,3 +1,17 @@
 pub mod bound_file;
+pub mod codec;
+pub mod connection;
+pub mod delta;
+pub mod frame;
+pub mod handshake;
+pub mod hard_links;
+pub mod local_changes;
+pub mod manifest;
+pub mod owner;
+pub mod pipe;
 pub mod remote_info;
+pub mod sparse;
+pub mod tcp;
+pub mod unix_socket;
 pub mod watcher;
+pub mod xattrs;
@@ -0,0 +1,233 @@
+use error::BindrsError;
+use nix::unistd::{self, FchownatFlags, Gid, Group, Uid, User};
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Who owns a file. Names are carried alongside ids so the receiver can resolve them to its own
+/// ids, falling back to the numeric ids when a name doesn't exist there.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Owner {
+    pub uid: u32,
+    pub user: Option<String>,
+    pub gid: u32,
+    pub group: Option<String>,
+}
+
+/// Maps users and groups between the local and remote machines, by name or numeric id.
+#[derive(Default, Debug)]
+pub struct OwnerMap {
+    users: Vec<(Id, Id)>,
+    groups: Vec<(Id, Id)>,
+}
+
+#[derive(PartialEq, Debug)]
+enum Id {
+    Name(String),
+    Number(u32),
+}
+
+impl Owner {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let (uid, gid) = (metadata.uid(), metadata.gid());
+        Self {
+            uid,
+            user: User::from_uid(Uid::from_raw(uid)).ok().and_then(|user| user.map(|u| u.name)),
+            gid,
+            group: Group::from_gid(Gid::from_raw(gid)).ok().and_then(|group| group.map(|g| g.name)),
+        }
+    }
+
+    /// Changes ownership of the path itself, never a symlink's target. Only root can give files
+    /// away, so this does nothing for anyone else.
+    pub fn apply(&self, full_path: &Path) -> Result<(), BindrsError> {
+        if !unistd::geteuid().is_root() {
+            return Ok(());
+        }
+
+        let uid = match self.user.as_ref().and_then(|name| User::from_name(name).ok().and_then(|u| u)) {
+            Some(user) => user.uid,
+            None => Uid::from_raw(self.uid),
+        };
+        let gid = match self.group.as_ref().and_then(|name| Group::from_name(name).ok().and_then(|g| g)) {
+            Some(group) => group.gid,
+            None => Gid::from_raw(self.gid),
+        };
+        unistd::fchownat(None, full_path, Some(uid), Some(gid), FchownatFlags::NoFollowSymlink).map_err(|e| {
+            BindrsError::File(format!("Failed to set owner at: {}: {}", full_path.display(), e))
+        })
+    }
+}
+
+impl OwnerMap {
+    /// Adds a `<local>=<remote>` user mapping.
+    pub fn add_user(&mut self, mapping: &str) -> Result<(), BindrsError> {
+        self.users.push(parse_mapping(mapping)?);
+        Ok(())
+    }
+
+    /// Adds a `<local>=<remote>` group mapping.
+    pub fn add_group(&mut self, mapping: &str) -> Result<(), BindrsError> {
+        self.groups.push(parse_mapping(mapping)?);
+        Ok(())
+    }
+
+    pub fn to_remote(&self, owner: Owner) -> Owner {
+        let (user, uid) = map_id(&self.users, false, owner.user, owner.uid);
+        let (group, gid) = map_id(&self.groups, false, owner.group, owner.gid);
+        Owner { uid, user, gid, group }
+    }
+
+    pub fn to_local(&self, owner: Owner) -> Owner {
+        let (user, uid) = map_id(&self.users, true, owner.user, owner.uid);
+        let (group, gid) = map_id(&self.groups, true, owner.group, owner.gid);
+        Owner { uid, user, gid, group }
+    }
+
+    /// The same mappings in the form rsync's `--usermap` and `--groupmap` take.
+    pub fn rsync_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if !self.users.is_empty() {
+            args.push(format!("--usermap={}", rsync_mappings(&self.users)));
+        }
+        if !self.groups.is_empty() {
+            args.push(format!("--groupmap={}", rsync_mappings(&self.groups)));
+        }
+        args
+    }
+}
+
+impl Id {
+    fn parse(id: &str) -> Self {
+        match id.parse() {
+            Ok(number) => Id::Number(number),
+            Err(_) => Id::Name(id.to_owned()),
+        }
+    }
+
+    fn matches(&self, name: &Option<String>, number: u32) -> bool {
+        match *self {
+            Id::Name(ref n) => name.as_ref() == Some(n),
+            Id::Number(n) => n == number,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match *self {
+            Id::Name(ref n) => n.clone(),
+            Id::Number(n) => n.to_string(),
+        }
+    }
+}
+
+fn parse_mapping(mapping: &str) -> Result<(Id, Id), BindrsError> {
+    let mut parts = mapping.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(local), Some(remote)) if !local.is_empty() && !remote.is_empty() => Ok((Id::parse(local), Id::parse(remote))),
+        _ => Err(BindrsError::Config(
+            format!("Invalid owner mapping {}, expected <local>=<remote>", mapping),
+        )),
+    }
+}
+
+/// Swaps a matching name or id for the other side of its mapping. A numeric mapping drops the
+/// name so the id is used as-is, while a named one keeps the original id as the fallback.
+fn map_id(mappings: &[(Id, Id)], reverse: bool, name: Option<String>, number: u32) -> (Option<String>, u32) {
+    let mapped = mappings.iter().map(|&(ref local, ref remote)| if reverse {
+        (remote, local)
+    } else {
+        (local, remote)
+    }).find(|&(from, _)| from.matches(&name, number));
+
+    match mapped {
+        Some((_, &Id::Name(ref n))) => (Some(n.clone()), number),
+        Some((_, &Id::Number(n))) => (None, n),
+        None => (name, number),
+    }
+}
+
+fn rsync_mappings(mappings: &[(Id, Id)]) -> String {
+    mappings
+        .iter()
+        .map(|&(ref local, ref remote)| format!("{}:{}", local.describe(), remote.describe()))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use test_support::test_dir;
+
+    fn owner(user: &str, uid: u32) -> Owner {
+        Owner {
+            uid,
+            user: Some(user.to_owned()),
+            gid: 100,
+            group: Some("staff".to_owned()),
+        }
+    }
+
+    #[test]
+    fn mappings_apply_in_both_directions() {
+        let mut map = OwnerMap::default();
+        map.add_user("alice=bob").unwrap();
+        map.add_group("staff=1500").unwrap();
+
+        let remote = map.to_remote(owner("alice", 1000));
+        assert_eq!(Some("bob".to_owned()), remote.user);
+        assert_eq!(1000, remote.uid);
+        assert_eq!(None, remote.group);
+        assert_eq!(1500, remote.gid);
+
+        let local = map.to_local(owner("bob", 1001));
+        assert_eq!(Some("alice".to_owned()), local.user);
+        assert!(map.to_local(owner("carol", 1002)) == owner("carol", 1002));
+    }
+
+    #[test]
+    fn invalid_mappings_are_rejected() {
+        let mut map = OwnerMap::default();
+        assert!(map.add_user("alice").is_err());
+        assert!(map.add_user("=bob").is_err());
+        assert_eq!(Vec::<String>::new(), map.rsync_args());
+    }
+
+    #[test]
+    fn received_owners_are_mapped_then_applied() {
+        let (_dir, dir) = test_dir("bindrs-owner");
+        let path = Path::new(&dir).join("f");
+        fs::write(&path, b"").unwrap();
+        let mut map = OwnerMap::default();
+        map.add_user("nobody=remote-only-user").unwrap();
+        map.add_group("4321=1234").unwrap();
+        let received = Owner {
+            uid: 5000,
+            user: Some("remote-only-user".to_owned()),
+            gid: 1234,
+            group: None,
+        };
+
+        // Only root can give files away, to a user that exists here
+        let nobody = User::from_name("nobody").unwrap();
+        if !unistd::geteuid().is_root() || nobody.is_none() {
+            return;
+        }
+        map.to_local(received).apply(&path).unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert_eq!(nobody.unwrap().uid.as_raw(), metadata.uid());
+        assert_eq!(4321, metadata.gid());
+
+        // Names that don't exist here fall back to the ids they came with
+        let unknown = Owner {
+            uid: 5000,
+            user: Some("remote-only-user".to_owned()),
+            gid: 6000,
+            group: Some("remote-only-group".to_owned()),
+        };
+        unknown.apply(&path).unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert_eq!((5000, 6000), (metadata.uid(), metadata.gid()));
+    }
+}
@@ -0,0 +1,89 @@
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// An in-memory byte stream, so both halves of a local sync can run in one process.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let (tx, rx) = mpsc::channel();
+    (
+        PipeReader {
+            rx,
+            chunk: vec![],
+            pos: 0,
+        },
+        PipeWriter { tx },
+    )
+}
+
+/// Reads what the `PipeWriter` wrote, ending once it's dropped.
+#[derive(Debug)]
+pub struct PipeReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+/// Writes to the `PipeReader`, failing once it's dropped.
+#[derive(Debug)]
+pub struct PipeWriter {
+    tx: Sender<Vec<u8>>,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // Writer dropped
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let read = (&self.chunk[self.pos..]).read(buf)?;
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.tx.send(buf.to_vec()).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "Other half of the local sync stopped")
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn bytes_arrive_in_order_until_either_end_is_dropped() {
+        let (mut reader, mut writer) = pipe();
+        let handle = thread::spawn(move || {
+            writer.write_all(b"hello ").unwrap();
+            writer.write_all(b"world").unwrap();
+        });
+
+        let mut received = String::new();
+        reader.read_to_string(&mut received).unwrap();
+        handle.join().unwrap();
+        assert_eq!("hello world", received);
+
+        let (reader, mut writer) = pipe();
+        drop(reader);
+        assert_eq!(io::ErrorKind::BrokenPipe, writer.write(b"lost").unwrap_err().kind());
+    }
+}
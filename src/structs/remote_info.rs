@@ -1,48 +1,65 @@
-use helpers;
+use error::BindrsError;
 use regex::Regex;
-use slog::Logger;
 use std::io;
+use std::path::Path;
 use std::process::Command;
 
 pub struct RemoteInfo {
     pub is_remote: bool,
     pub path: String,
-    pub user: String,
-    pub host: String,
-    pub port: String,
+    // `user@host`, or a host that ssh's own config fills in the rest for
+    pub destination: String,
+    ssh_program: String,
+    // Shared by every ssh connection to the remote, rsync's included
+    ssh_args: Vec<String>,
 }
 
 impl RemoteInfo {
-    pub fn build(remote_dir: &str, port: Option<&str>) -> Self {
+    /// `ssh_command` is split on spaces into the program and its first arguments, followed by
+    /// `ssh_options` as `-o` options and `port`, only if given.
+    pub fn build(remote_dir: &str, port: Option<&str>, ssh_command: &str, ssh_options: &[String]) -> Self {
         #[cfg_attr(feature="clippy", allow(result_unwrap_used))]
         // Unwrap is safe - hard coded string
-        let regex = Regex::new("([^@]+)@([^:]+):(.+)").unwrap();
-        if let Some(captures) = regex.captures(remote_dir) {
+        let regex = Regex::new("^((?:[^@/:]+@)?[^@/:]+):(.+)$").unwrap();
+        let mut ssh_command = ssh_command.split_whitespace().map(|arg| arg.to_owned());
+        let ssh_program = ssh_command.next().unwrap_or_else(|| "ssh".to_owned());
+        let mut ssh_args: Vec<String> = ssh_command.collect();
+        for option in ssh_options {
+            ssh_args.push("-o".to_owned());
+            ssh_args.push(option.clone());
+        }
+        if let Some(p) = port {
+            ssh_args.push("-p".to_owned());
+            ssh_args.push(p.to_owned());
+        }
+
+        // `data:backup` is a local folder, not `backup` on host `data`, when `data` exists here
+        let captures = regex.captures(remote_dir).filter(|captures| {
+            !Path::new(remote_dir).exists() && !Path::new(&captures[1]).exists()
+        });
+        if let Some(captures) = captures {
             Self {
                 is_remote: true,
                 // Unwrap is safe - capture group exists in regex
-                path: captures.get(3).unwrap().as_str().to_owned(),
-                user: captures.get(1).unwrap().as_str().to_owned(),
-                host: captures.get(2).unwrap().as_str().to_owned(),
-                port: match port {
-                    Some(p) => p.to_owned(),
-                    None => "22".to_owned(),
-                },
+                path: captures.get(2).unwrap().as_str().to_owned(),
+                destination: captures.get(1).unwrap().as_str().to_owned(),
+                ssh_program,
+                ssh_args,
             }
         } else {
             Self {
                 is_remote: false,
                 path: remote_dir.to_owned(),
-                user: "".to_owned(),
-                host: "".to_owned(),
-                port: "".to_owned(),
+                destination: "".to_owned(),
+                ssh_program,
+                ssh_args,
             }
         }
     }
 
     pub fn base_command(&self, cmd: &str) -> Command {
         if self.is_remote {
-            Command::new("ssh")
+            Command::new(&self.ssh_program)
         } else {
             let mut iter = cmd.split_whitespace();
             let main_cmd = iter.next().unwrap_or("");
@@ -54,11 +71,10 @@ impl RemoteInfo {
     pub fn generate_command<'a>(&self, command: &'a mut Command, cmd: &str) -> &'a mut Command {
         if self.is_remote {
             command
+                .args(&self.ssh_args)
                 .arg("-q")
-                .arg(format!("{}@{}", self.user, self.host))
-                .arg("-p")
-                .arg(&self.port)
                 .arg("-C")
+                .arg(&self.destination)
                 .arg(cmd)
         } else {
             let iter = cmd.split_whitespace();
@@ -71,9 +87,16 @@ impl RemoteInfo {
         }
     }
 
+    /// The ssh command for rsync's `-e`, quoted the way rsync splits it back up.
+    pub fn rsync_shell(&self) -> String {
+        let mut shell = vec![rsync_quote(&self.ssh_program)];
+        shell.extend(self.ssh_args.iter().map(|arg| rsync_quote(arg)));
+        shell.join(" ")
+    }
+
     pub fn full_path(&self) -> String {
         if self.is_remote {
-            format!("{}@{}:{}", self.user, self.host, self.path)
+            format!("{}:{}", self.destination, self.path)
         } else {
             self.path.clone()
         }
@@ -83,25 +106,21 @@ impl RemoteInfo {
         format!("{}/", self.full_path())
     }
 
+    /// Runs `cmd`, returning its output only if it was wanted.
     pub fn check_cmd_output(
         &self,
-        log: &Logger,
         cmd: &str,
         wanted_output: &[String],
         match_output: bool,
-    ) -> Result<String, ()> {
-        match self.get_cmd_output(cmd) {
-            Ok(output) => {
-                if match_output ^ wanted_output.contains(&output) {
-                    Err(())
-                } else {
-                    Ok(output)
-                }
-            }
-            Err(e) => {
-                helpers::log_error_and_exit(log, &format!("Failed to run '{}' on remote: {}", cmd, e));
-                panic!(); // For compilation
-            }
+    ) -> Result<Option<String>, BindrsError> {
+        let output = self.get_cmd_output(cmd).map_err(|e| {
+            BindrsError::Remote(format!("Failed to run '{}' on remote: {}", cmd, e))
+        })?;
+
+        if match_output ^ wanted_output.contains(&output) {
+            Ok(None)
+        } else {
+            Ok(Some(output))
         }
     }
 
@@ -121,3 +140,48 @@ impl RemoteInfo {
         }
     }
 }
+
+/// rsync only understands quotes, where a doubled quote inside is a literal one.
+fn rsync_quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '\'' || c == '"') {
+        format!("'{}'", arg.replace('\'', "''"))
+    } else {
+        arg.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssh_options_apply_to_commands_and_rsync() {
+        let options = vec!["ProxyJump=bastion".to_owned(), "ProxyCommand=nc -x 'proxy' %h %p".to_owned()];
+        let remote_info = RemoteInfo::build("alias:/srv/dir", Some("2222"), "ssh -F cfg", &options);
+        assert!(remote_info.is_remote);
+        assert_eq!("alias", remote_info.destination);
+        assert_eq!("alias:/srv/dir", remote_info.full_path());
+        assert_eq!(
+            "ssh -F cfg -o ProxyJump=bastion -o 'ProxyCommand=nc -x ''proxy'' %h %p' -p 2222",
+            remote_info.rsync_shell()
+        );
+
+        let command = format!("{:?}", remote_info.generate_command(&mut remote_info.base_command("ls"), "ls"));
+        assert!(command.starts_with("\"ssh\" \"-F\" \"cfg\" \"-o\" \"ProxyJump=bastion\""));
+        assert!(command.ends_with("\"-p\" \"2222\" \"-q\" \"-C\" \"alias\" \"ls\""));
+
+        let remote_info = RemoteInfo::build("user@host:dir", None, "ssh", &[]);
+        assert_eq!("user@host", remote_info.destination);
+        assert_eq!("ssh", remote_info.rsync_shell());
+        assert!(!RemoteInfo::build("./some:dir", None, "ssh", &[]).is_remote);
+    }
+
+    #[test]
+    fn existing_local_paths_are_never_remote() {
+        // Tests run from the crate root
+        let remote_info = RemoteInfo::build("src:backup", None, "ssh", &[]);
+        assert!(!remote_info.is_remote);
+        assert_eq!("src:backup", remote_info.full_path());
+        assert!(RemoteInfo::build("bindrs-missing-host:backup", None, "ssh", &[]).is_remote);
+    }
+}
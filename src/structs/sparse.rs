@@ -0,0 +1,126 @@
+use std::fs::{File, Metadata};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+
+/// Whether the file takes up less space on disk than its length, so it has holes worth keeping.
+pub fn is_sparse(metadata: &Metadata) -> bool {
+    metadata.is_file() && metadata.blocks() * 512 < metadata.len()
+}
+
+/// Finds the first run of data at or after `offset`, as its start and end. The start is `len`
+/// when only a hole is left. Filesystems that can't report holes give back the whole rest.
+#[cfg(any(target_os = "dragonfly", target_os = "freebsd", target_os = "linux"))]
+pub fn next_data(file: &File, offset: u64, len: u64) -> io::Result<(u64, u64)> {
+    use nix::errno::Errno;
+    use nix::unistd::{lseek, Whence};
+    use nix::Error;
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let start = match lseek(fd, offset as i64, Whence::SeekData) {
+        Ok(start) => start as u64,
+        // Past the last data
+        Err(Error::Sys(Errno::ENXIO)) => return Ok((len, len)),
+        Err(Error::Sys(Errno::EINVAL)) => return Ok((offset, len)),
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+    };
+    if start >= len {
+        return Ok((len, len));
+    }
+
+    let end = lseek(fd, start as i64, Whence::SeekHole).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    Ok((start, (end as u64).min(len)))
+}
+
+/// Without `SEEK_DATA` and `SEEK_HOLE`, such as on macOS, the whole rest of the file is data.
+#[cfg(not(any(target_os = "dragonfly", target_os = "freebsd", target_os = "linux")))]
+pub fn next_data(_file: &File, offset: u64, len: u64) -> io::Result<(u64, u64)> {
+    Ok((offset, len))
+}
+
+/// Writes a new file, seeking over the holes it's told about instead of writing zeros so they
+/// take no space.
+pub struct SparseWriter {
+    inner: BufWriter<File>,
+    hole: u64,
+    len: u64,
+}
+
+impl SparseWriter {
+    pub fn new(file: File) -> Self {
+        Self {
+            inner: BufWriter::new(file),
+            hole: 0,
+            len: 0,
+        }
+    }
+
+    /// Leaves the next `len` bytes as a hole.
+    pub fn skip(&mut self, len: u64) {
+        self.hole += len;
+        self.len += len;
+    }
+
+    /// Sizes the file to cover a trailing hole and hands back the writer to be flushed.
+    pub fn finish(mut self) -> io::Result<BufWriter<File>> {
+        if self.hole > 0 {
+            self.inner.seek(SeekFrom::Start(self.len))?;
+            self.inner.get_ref().set_len(self.len)?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl Write for SparseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.hole > 0 {
+            self.inner.seek(SeekFrom::Current(self.hole as i64))?;
+            self.hole = 0;
+        }
+        let written = self.inner.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn skipped_holes_are_found_again() {
+        let dir = TempDir::new("bindrs-sparse").unwrap();
+        let path = dir.path().join("sparse");
+        let mut writer = SparseWriter::new(File::create(&path).unwrap());
+        writer.write_all(b"start").unwrap();
+        writer.skip(16 * 1024 * 1024);
+        writer.write_all(b"middle").unwrap();
+        writer.skip(16 * 1024 * 1024);
+        writer.finish().unwrap().flush().unwrap();
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(32 * 1024 * 1024 + 11, metadata.len());
+        assert!(is_sparse(&metadata));
+
+        let contents = fs::read(&path).unwrap();
+        assert_eq!(b"start", &contents[..5]);
+        assert_eq!(b"middle", &contents[5 + 16 * 1024 * 1024..11 + 16 * 1024 * 1024]);
+
+        let file = File::open(&path).unwrap();
+        let (start, end) = next_data(&file, 0, metadata.len()).unwrap();
+        assert_eq!(0, start);
+        if cfg!(any(target_os = "dragonfly", target_os = "freebsd", target_os = "linux")) {
+            assert!(end < metadata.len());
+            let (start, _) = next_data(&file, end, metadata.len()).unwrap();
+            assert!(start > end);
+        } else {
+            assert_eq!(metadata.len(), end);
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use bincode::{serialize, deserialize};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use error::BindrsError;
+use sha2::{Digest, Sha256};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use structs::connection::{self, set_handshake_timeout};
+
+// Both sides prove they hold the pre-shared key before anything else is sent, and everything
+// after is encrypted with keys derived from it and a fresh exchange
+const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+
+const MAX_MESSAGE_LEN: usize = 65_535;
+const TAG_LEN: usize = 16;
+const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+// Shorter keys are too easy to guess
+const MIN_KEY_LEN: usize = 16;
+
+/// A remote given as `tcp://host:port/path`, with `path` relative to the directory the remote
+/// listens in.
+#[derive(Debug)]
+pub struct TcpRemote {
+    pub address: String,
+    pub path: String,
+}
+
+/// What the master asks of a listener, sent inside the first handshake message.
+#[derive(Serialize, Deserialize)]
+pub struct ConnectRequest {
+    pub path: String,
+    pub ignores: Vec<String>,
+}
+
+/// Reads and decrypts messages from the remote.
+pub struct SecureReader {
+    stream: TcpStream,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+/// Encrypts each write as one message to the remote.
+pub struct SecureWriter {
+    stream: TcpStream,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+    message: Vec<u8>,
+}
+
+impl TcpRemote {
+    /// Anything not starting with `tcp://` is left for SSH or a local path.
+    pub fn parse(remote_dir: &str) -> Option<Self> {
+        if !remote_dir.starts_with("tcp://") {
+            return None;
+        }
+
+        let rest = &remote_dir["tcp://".len()..];
+        let (address, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_matches('/')),
+            None => (rest, ""),
+        };
+        Some(Self {
+            address: address.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+}
+
+/// Reads the pre-shared key from `path`. Any contents of at least 16 bytes will do, such as
+/// the output of `head -c 32 /dev/urandom | base64`.
+pub fn load_key(path: &str) -> Result<Vec<u8>, BindrsError> {
+    let contents = fs::read(path).map_err(|e| {
+        BindrsError::Config(format!("Failed to read key file {}: {}", path, e))
+    })?;
+    let key = String::from_utf8_lossy(&contents);
+    let key = key.trim();
+    if key.len() < MIN_KEY_LEN {
+        return Err(BindrsError::Config(
+            format!("Key file {} must hold at least {} bytes", path, MIN_KEY_LEN),
+        ));
+    }
+
+    let mut hasher = Sha256::default();
+    hasher.input(key.as_bytes());
+    Ok(hasher.result().to_vec())
+}
+
+/// Connects to a listener and proves both sides hold `key`, asking to sync `remote.path`.
+pub fn connect(remote: &TcpRemote, key: &[u8], ignores: &[String]) -> Result<(SecureReader, SecureWriter), BindrsError> {
+    let mut stream = TcpStream::connect(&remote.address).map_err(|e| {
+        BindrsError::Remote(format!("Failed to connect to {}: {}", remote.address, e))
+    })?;
+    set_handshake_timeout(&stream, true)?;
+
+    let mut noise = Builder::new(noise_params()?)
+        .psk(0, key)
+        .build_initiator()
+        .map_err(noise_error)?;
+    let request = serialize(&ConnectRequest {
+        path: remote.path.clone(),
+        ignores: ignores.to_vec(),
+    }).map_err(|e| BindrsError::Internal(format!("Failed to encode connect request: {}", e)))?;
+    write_handshake(&mut stream, &mut noise, &request)?;
+
+    let reply = read_handshake(&mut stream, &mut noise).map_err(|_| {
+        BindrsError::Remote(format!(
+            "{} refused the connection, check both sides use the same key",
+            remote.address
+        ))
+    })?;
+    let reply: Result<(), String> = deserialize(&reply).map_err(|e| {
+        BindrsError::Protocol(format!("Failed to decode reply from remote: {}", e))
+    })?;
+    reply.map_err(BindrsError::Remote)?;
+
+    into_transport(stream, noise)
+}
+
+/// Checks a master that connected holds `key`, then hands its request to `prepare`. Whatever
+/// `prepare` refuses is reported back to the master.
+pub fn accept<T, F>(mut stream: TcpStream, key: &[u8], prepare: F) -> Result<(T, SecureReader, SecureWriter), BindrsError>
+where
+    F: FnOnce(ConnectRequest) -> Result<T, BindrsError>,
+{
+    set_handshake_timeout(&stream, true)?;
+    let mut noise = Builder::new(noise_params()?)
+        .psk(0, key)
+        .build_responder()
+        .map_err(noise_error)?;
+
+    let request = read_handshake(&mut stream, &mut noise)?;
+    let prepared = deserialize(&request)
+        .map_err(|e| BindrsError::Protocol(format!("Failed to decode connect request: {}", e)))
+        .and_then(prepare);
+    let reply: Result<(), String> = match prepared {
+        Ok(_) => Ok(()),
+        Err(ref e) => Err(e.to_string()),
+    };
+    let reply = serialize(&reply).map_err(|e| BindrsError::Internal(format!("Failed to encode reply: {}", e)))?;
+    write_handshake(&mut stream, &mut noise, &reply)?;
+
+    let prepared = prepared?;
+    let (reader, writer) = into_transport(stream, noise)?;
+    Ok((prepared, reader, writer))
+}
+
+fn noise_params() -> Result<::snow::params::NoiseParams, BindrsError> {
+    NOISE_PARAMS.parse().map_err(noise_error)
+}
+
+fn noise_error(e: ::snow::Error) -> BindrsError {
+    BindrsError::Internal(format!("Failed to set up encryption: {}", e))
+}
+
+fn write_handshake(stream: &mut TcpStream, noise: &mut HandshakeState, payload: &[u8]) -> Result<(), BindrsError> {
+    let mut message = vec![0u8; MAX_MESSAGE_LEN];
+    let len = noise.write_message(payload, &mut message).map_err(|e| {
+        BindrsError::Config(format!("Failed to write handshake, the ignores may be too long: {}", e))
+    })?;
+    write_message(stream, &message[..len]).map_err(|e| {
+        BindrsError::Disconnected(format!("Couldn't write handshake to remote: {}", e))
+    })
+}
+
+fn read_handshake(stream: &mut TcpStream, noise: &mut HandshakeState) -> Result<Vec<u8>, BindrsError> {
+    let message = read_message(stream).map_err(|e| {
+        BindrsError::Disconnected(format!("Couldn't read handshake from remote: {}", e))
+    })?;
+    let mut payload = vec![0u8; MAX_MESSAGE_LEN];
+    let len = noise.read_message(&message, &mut payload).map_err(|_| {
+        BindrsError::Remote("Remote failed to authenticate, the keys don't match".to_owned())
+    })?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+fn into_transport(stream: TcpStream, noise: HandshakeState) -> Result<(SecureReader, SecureWriter), BindrsError> {
+    set_handshake_timeout(&stream, false)?;
+    let transport = Arc::new(noise.into_stateless_transport_mode().map_err(noise_error)?);
+    let read_stream = connection::split(&stream)?;
+
+    let reader = SecureReader {
+        stream: read_stream,
+        transport: transport.clone(),
+        nonce: 0,
+        plain: vec![],
+        pos: 0,
+    };
+    let writer = SecureWriter {
+        stream,
+        transport,
+        nonce: 0,
+        message: vec![0u8; MAX_MESSAGE_LEN + 2],
+    };
+    Ok((reader, writer))
+}
+
+fn write_message<W: Write>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    writer.write_u16::<BigEndian>(message.len() as u16)?;
+    writer.write_all(message)
+}
+
+fn read_message<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u16::<BigEndian>()?;
+    let mut message = vec![0u8; len as usize];
+    reader.read_exact(&mut message)?;
+    Ok(message)
+}
+
+impl SecureReader {
+    /// Another handle on the connection, so it can be shut down while this is still reading.
+    pub fn try_clone_stream(&self) -> Result<TcpStream, BindrsError> {
+        connection::split(&self.stream)
+    }
+}
+
+impl Read for SecureReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.plain.len() {
+            let message = read_message(&mut self.stream)?;
+            self.plain.resize(MAX_MESSAGE_LEN, 0);
+            let len = self.transport
+                .read_message(self.nonce, &message, &mut self.plain)
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Failed to decrypt message from remote")
+                })?;
+            self.nonce += 1;
+            self.plain.truncate(len);
+            self.pos = 0;
+        }
+
+        let read = (&self.plain[self.pos..]).read(buf)?;
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+impl Write for SecureWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let payload = &buf[..buf.len().min(MAX_PAYLOAD_LEN)];
+        let len = self.transport
+            .write_message(self.nonce, payload, &mut self.message[2..])
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to encrypt message: {}", e)))?;
+        self.nonce += 1;
+        (&mut self.message[..2]).write_u16::<BigEndian>(len as u16)?;
+        self.stream.write_all(&self.message[..len + 2])?;
+        Ok(payload.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn accept_one(key: &'static [u8]) -> (String, thread::JoinHandle<Result<String, BindrsError>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (path, mut reader, mut writer) = accept(stream, key, |request| Ok(request.path))?;
+            let mut received = vec![0u8; 100_000];
+            reader.read_exact(&mut received).unwrap();
+            writer.write_all(&received).unwrap();
+            Ok(path)
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn remote_spec_is_split_into_address_and_path() {
+        let remote = TcpRemote::parse("tcp://host:4000/some/dir/").unwrap();
+        assert_eq!("host:4000", remote.address);
+        assert_eq!("some/dir", remote.path);
+        assert_eq!("", TcpRemote::parse("tcp://host:4000").unwrap().path);
+        assert!(TcpRemote::parse("user@host:/dir").is_none());
+    }
+
+    #[test]
+    fn matching_keys_get_an_encrypted_stream() {
+        let (address, handle) = accept_one(b"0123456789abcdef0123456789abcdef");
+        let remote = TcpRemote { address, path: "dir".to_owned() };
+        let (mut reader, mut writer) = connect(&remote, b"0123456789abcdef0123456789abcdef", &[]).unwrap();
+
+        // Bigger than a single message
+        let sent: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        writer.write_all(&sent).unwrap();
+        let mut echoed = vec![0u8; sent.len()];
+        reader.read_exact(&mut echoed).unwrap();
+        assert!(echoed == sent);
+        assert_eq!("dir", handle.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn mismatched_keys_are_refused() {
+        let (address, handle) = accept_one(b"0123456789abcdef0123456789abcdef");
+        let remote = TcpRemote { address, path: String::new() };
+        match connect(&remote, b"fedcba9876543210fedcba9876543210", &[]) {
+            Err(BindrsError::Remote(_)) => (),
+            _ => panic!("expected a mismatched key to be refused"),
+        }
+        match handle.join().unwrap() {
+            Err(BindrsError::Remote(_)) => (),
+            _ => panic!("expected the listener to refuse a mismatched key"),
+        }
+    }
+}
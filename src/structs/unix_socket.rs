@@ -0,0 +1,152 @@
+use bincode::{serialize_into, deserialize_from};
+use error::BindrsError;
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use structs::connection::{self, set_handshake_timeout};
+
+/// A remote given as `unix://<socket path>`, served by a `bindrs slave --socket` on this machine.
+#[derive(Debug)]
+pub struct SocketRemote {
+    pub path: String,
+}
+
+impl SocketRemote {
+    /// Anything not starting with `unix://` is left for the other transports.
+    pub fn parse(remote_dir: &str) -> Option<Self> {
+        if !remote_dir.starts_with("unix://") {
+            return None;
+        }
+
+        Some(Self { path: remote_dir["unix://".len()..].to_owned() })
+    }
+}
+
+/// Listens on `path`, replacing a socket left behind by a slave that didn't shut down cleanly.
+pub fn bind(path: &str) -> Result<UnixListener, BindrsError> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(BindrsError::Config(format!("{} exists and isn't a socket", path)));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(BindrsError::Config(format!("Another slave is already listening on {}", path)));
+        }
+        fs::remove_file(path).map_err(|e| {
+            BindrsError::Config(format!("Failed to remove stale socket {}: {}", path, e))
+        })?;
+    }
+
+    UnixListener::bind(path).map_err(|e| {
+        BindrsError::Config(format!("Failed to listen on {}: {}", path, e))
+    })
+}
+
+/// Attaches to a slave, passing on the ignores it should use along with its own.
+pub fn connect(remote: &SocketRemote, ignores: &[String]) -> Result<(UnixStream, UnixStream), BindrsError> {
+    let mut stream = UnixStream::connect(&remote.path).map_err(|e| {
+        BindrsError::Remote(format!(
+            "Failed to connect to {}, check `bindrs slave --socket` is running there: {}",
+            remote.path,
+            e
+        ))
+    })?;
+    set_handshake_timeout(&stream, true)?;
+
+    serialize_into(&mut stream, ignores).map_err(|e| {
+        BindrsError::Disconnected(format!("Couldn't send ignores to remote: {}", e))
+    })?;
+    let reply: Result<(), String> = deserialize_from(&mut stream).map_err(|e| {
+        BindrsError::Disconnected(format!("Couldn't read reply from remote: {}", e))
+    })?;
+    reply.map_err(BindrsError::Remote)?;
+
+    split(stream)
+}
+
+/// Reads the ignores a master attached with and hands them to `prepare`. Whatever `prepare`
+/// refuses is reported back to the master.
+pub fn accept<T, F>(mut stream: UnixStream, prepare: F) -> Result<(T, UnixStream, UnixStream), BindrsError>
+where
+    F: FnOnce(Vec<String>) -> Result<T, BindrsError>,
+{
+    set_handshake_timeout(&stream, true)?;
+    let prepared = deserialize_from(&mut stream)
+        .map_err(|e| BindrsError::Protocol(format!("Failed to decode ignores from master: {}", e)))
+        .and_then(prepare);
+    let reply: Result<(), String> = match prepared {
+        Ok(_) => Ok(()),
+        Err(ref e) => Err(e.to_string()),
+    };
+    serialize_into(&mut stream, &reply).map_err(|e| {
+        BindrsError::Disconnected(format!("Couldn't reply to master: {}", e))
+    })?;
+
+    let prepared = prepared?;
+    let (reader, writer) = split(stream)?;
+    Ok((prepared, reader, writer))
+}
+
+fn split(stream: UnixStream) -> Result<(UnixStream, UnixStream), BindrsError> {
+    set_handshake_timeout(&stream, false)?;
+    let reader = connection::split(&stream)?;
+    Ok((reader, stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::thread;
+    use tempdir::TempDir;
+
+    #[test]
+    fn stale_sockets_are_replaced_and_ignores_passed_on() {
+        let dir = TempDir::new("bindrs-socket").unwrap();
+        let path = dir.path().join("slave.sock").to_str().unwrap().to_owned();
+
+        // Left behind by a slave that was killed
+        drop(bind(&path).unwrap());
+        let listener = bind(&path).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (ignores, mut reader, mut writer) = accept(stream, Ok).unwrap();
+            let mut received = [0u8; 4];
+            reader.read_exact(&mut received).unwrap();
+            writer.write_all(&received).unwrap();
+            (ignores, listener)
+        });
+
+        let remote = SocketRemote::parse(&format!("unix://{}", path)).unwrap();
+        let (mut reader, mut writer) = connect(&remote, &["log".to_owned()]).unwrap();
+        writer.write_all(b"ping").unwrap();
+        let mut echoed = [0u8; 4];
+        reader.read_exact(&mut echoed).unwrap();
+        assert_eq!(b"ping", &echoed);
+        let (ignores, _listener) = handle.join().unwrap();
+        assert!(ignores == vec!["log".to_owned()]);
+
+        match bind(&path) {
+            Err(BindrsError::Config(_)) => (),
+            _ => panic!("expected a live socket to be left alone"),
+        }
+    }
+
+    #[test]
+    fn refusals_reach_the_master() {
+        let dir = TempDir::new("bindrs-socket").unwrap();
+        let path = dir.path().join("slave.sock").to_str().unwrap().to_owned();
+        let listener = bind(&path).unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            accept(stream, |_| -> Result<(), BindrsError> {
+                Err(BindrsError::Config("bad ignore".to_owned()))
+            }).unwrap_err();
+        });
+
+        match connect(&SocketRemote { path }, &[]) {
+            Err(BindrsError::Remote(ref msg)) if msg.contains("bad ignore") => (),
+            _ => panic!("expected the refusal to be passed on"),
+        }
+    }
+}
@@ -1,11 +1,13 @@
-use helpers;
+use error::BindrsError;
 use notify::{DebouncedEvent, RecommendedWatcher, Watcher, RecursiveMode, watcher};
 use regex::RegexSet;
-use slog::Logger;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
-use structs::bound_file::FileAction;
+use structs::bound_file::{is_symlink, FileAction};
 
 #[cfg_attr(feature = "clippy", allow(stutter))]
 pub struct BindrsWatcher {
@@ -29,61 +31,172 @@ impl BindrsWatcher {
         }
     }
 
-    pub fn watch(&mut self, log: &Logger) {
+    /// Starts watching. Changes arrive on `rx`, which disconnects if watching stops.
+    pub fn watch(&mut self) -> Result<(), BindrsError> {
         let (final_tx, final_rx) = channel();
         let (notify_tx, notify_rx) = channel();
         let (watch_loop_tx, watch_loop_rx) = channel();
 
-        let mut watcher = watcher(notify_tx, Duration::from_millis(200)).unwrap_or_else(|e| {
-            helpers::log_error_and_exit(log, &format!("Failed to create watcher: {}", e));
-            panic!(e);
-        });
-        watcher
-            .watch(&self.dir, RecursiveMode::Recursive)
-            .unwrap_or_else(|e| {
-                helpers::log_error_and_exit(log, &format!("Failed to watch {}: {}", self.dir, e))
-            });
+        let mut watcher = watcher(notify_tx, Duration::from_millis(200)).map_err(|e| {
+            BindrsError::Watch(format!("Failed to create watcher: {}", e))
+        })?;
+        watcher.watch(&self.dir, RecursiveMode::Recursive).map_err(|e| {
+            BindrsError::Watch(format!("Failed to watch {}: {}", self.dir, e))
+        })?;
         self.watcher = Some(watcher);
         self.watch_loop_tx = Some(watch_loop_tx);
         self.rx = Some(final_rx);
 
         self.thread = {
+            let dir = self.dir.clone();
             let dir_length = self.dir.len() + 1;
             let ignores = self.ignores.clone();
-            let log_clone = log.clone();
+            // Removed paths can't be inspected, so remember which ones were directories
+            let mut known_dirs = HashSet::new();
+            find_directories(&self.dir, "", &self.ignores, &mut known_dirs);
             Some(thread::spawn(move || loop {
-                let event = notify_rx.recv().unwrap_or_else(|e| {
-                    helpers::log_error_and_exit(&log_clone, &format!("watch error: {}", e));
-                    panic!(e);
-                });
+                let event = match notify_rx.recv() {
+                    Ok(event) => event,
+                    Err(_) => break,
+                };
                 match watch_loop_rx.try_recv() {
                     Ok(_) |
                     Err(TryRecvError::Disconnected) => break,
                     Err(TryRecvError::Empty) => (),
                 };
+                let short_path = |p: &PathBuf| p.to_str().map(|path| path.chars().skip(dir_length).collect::<String>());
                 let actions = match event {
                     DebouncedEvent::Create(p) |
                     DebouncedEvent::Write(p) => vec![(FileAction::CreateUpdate, p)],
+                    DebouncedEvent::Chmod(p) => vec![(FileAction::Chmod, p)],
                     DebouncedEvent::Remove(p) => vec![(FileAction::Delete, p)],
-                    DebouncedEvent::Rename(p1, p2) => vec![(FileAction::Delete, p1), (FileAction::CreateUpdate, p2)],
+                    DebouncedEvent::Rename(p1, p2) => {
+                        match short_path(&p1) {
+                            // Moving out of or into an ignored path looks like a delete or create
+                            Some(ref from) if ignores.is_match(from) => vec![(FileAction::CreateUpdate, p2)],
+                            Some(from) => {
+                                match short_path(&p2) {
+                                    Some(ref to) if ignores.is_match(to) => vec![(FileAction::Delete, p1)],
+                                    Some(_) => vec![(FileAction::Rename { from }, p2)],
+                                    None => vec![(FileAction::Delete, p1)],
+                                }
+                            }
+                            None => vec![(FileAction::CreateUpdate, p2)],
+                        }
+                    }
                     _ => vec![],
                 };
 
                 let filtered_actions = actions
                     .into_iter()
-                    .filter_map(|(t, p)| match p.to_str() {
-                        Some(path) => {
-                            let short_path: String = path.chars().skip(dir_length).collect();
-                            Some((t, short_path))
-                        }
-                        None => None,
-                    })
+                    .filter_map(|(t, p)| short_path(&p).map(|path| (t, path)))
                     .filter(|&(_, ref short_path)| !ignores.is_match(short_path));
 
                 for (t, p) in filtered_actions {
-                    let _ = final_tx.send((t, p.to_owned()));
+                    for action in classify_directory_action(&dir, &ignores, t, p, &mut known_dirs) {
+                        let _ = final_tx.send(action);
+                    }
                 }
             }))
         };
+        Ok(())
+    }
+}
+
+fn classify_directory_action(
+    base_dir: &str,
+    ignores: &RegexSet,
+    action: FileAction,
+    path: String,
+    known_dirs: &mut HashSet<String>,
+) -> Vec<(FileAction, String)> {
+    match action {
+        FileAction::CreateUpdate => {
+            let full_str_path = format!("{}/{}", base_dir, path);
+            let full_path = Path::new(&full_str_path);
+            if full_path.is_dir() && !is_symlink(full_path) {
+                // Anything created inside before the directory was watched would otherwise be missed
+                let mut actions = vec![];
+                walk_children(base_dir, &path, ignores, &mut |child_action, child| {
+                    if child_action == FileAction::CreateDir {
+                        known_dirs.insert(child.clone());
+                    }
+                    actions.push((child_action, child));
+                });
+                known_dirs.insert(path.clone());
+                actions.insert(0, (FileAction::CreateDir, path));
+                actions
+            } else {
+                vec![(action, path)]
+            }
+        }
+        FileAction::Rename { from } => {
+            if known_dirs.remove(&from) {
+                let child_prefix = format!("{}/", from);
+                let children: Vec<String> = known_dirs
+                    .iter()
+                    .filter(|dir| dir.starts_with(&child_prefix))
+                    .cloned()
+                    .collect();
+                for child in children {
+                    known_dirs.remove(&child);
+                    known_dirs.insert(format!("{}/{}", path, &child[child_prefix.len()..]));
+                }
+                known_dirs.insert(path.clone());
+            }
+            vec![(FileAction::Rename { from }, path)]
+        }
+        FileAction::Delete => {
+            if known_dirs.remove(&path) {
+                let child_prefix = format!("{}/", path);
+                known_dirs.retain(|dir| !dir.starts_with(&child_prefix));
+                vec![(FileAction::DeleteDir, path)]
+            } else {
+                vec![(action, path)]
+            }
+        }
+        _ => vec![(action, path)],
+    }
+}
+
+fn find_directories(base_dir: &str, path: &str, ignores: &RegexSet, known_dirs: &mut HashSet<String>) {
+    walk_children(base_dir, path, ignores, &mut |action, child| {
+        if action == FileAction::CreateDir {
+            known_dirs.insert(child);
+        }
+    });
+}
+
+/// Reports every non-ignored path below `path` as the action that would recreate it.
+pub fn walk_children<F: FnMut(FileAction, String)>(base_dir: &str, path: &str, ignores: &RegexSet, found: &mut F) {
+    let full_str_path = if path.is_empty() {
+        base_dir.to_owned()
+    } else {
+        format!("{}/{}", base_dir, path)
+    };
+    let entries = match fs::read_dir(&full_str_path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let child = if path.is_empty() {
+            name
+        } else {
+            format!("{}/{}", path, name)
+        };
+
+        if ignores.is_match(&child) {
+            continue;
+        }
+
+        if is_dir {
+            found(FileAction::CreateDir, child.clone());
+            walk_children(base_dir, &child, ignores, found);
+        } else {
+            found(FileAction::CreateUpdate, child);
+        }
     }
 }
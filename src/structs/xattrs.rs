@@ -0,0 +1,162 @@
+use error::BindrsError;
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use xattr;
+
+// POSIX ACLs live in the system namespace on Linux, so syncing it carries them too
+const NAMESPACES: [&str; 4] = ["user", "trusted", "security", "system"];
+
+/// The attribute namespaces synced for a session, empty when extended attributes are off.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Default, Debug)]
+pub struct XattrNamespaces {
+    mask: u8,
+}
+
+/// Extended attributes read from a file, along with the namespaces they were read from so the
+/// receiver knows which of its own attributes are stale.
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Xattrs {
+    pub namespaces: XattrNamespaces,
+    pub attrs: Vec<(String, Vec<u8>)>,
+}
+
+impl XattrNamespaces {
+    pub fn from_names<'a, I: IntoIterator<Item = &'a str>>(names: I) -> Result<Self, BindrsError> {
+        let mut mask = 0;
+        for name in names {
+            match NAMESPACES.iter().position(|namespace| *namespace == name) {
+                Some(index) => mask |= 1 << index,
+                None => return Err(BindrsError::Config(format!("Unknown extended attribute namespace: {}", name))),
+            }
+        }
+        Ok(Self { mask })
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        NAMESPACES
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| self.mask & (1 << index) != 0)
+            .map(|(_, namespace)| *namespace)
+            .collect()
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.mask == 0
+    }
+
+    pub fn all() -> Self {
+        Self { mask: (1 << NAMESPACES.len()) - 1 }
+    }
+
+    pub fn filter<F: Fn(&str) -> bool>(self, keep: F) -> Self {
+        let mut mask = 0;
+        for (index, namespace) in NAMESPACES.iter().enumerate() {
+            if self.mask & (1 << index) != 0 && keep(namespace) {
+                mask |= 1 << index;
+            }
+        }
+        Self { mask }
+    }
+
+    fn contains(self, attr: &str) -> bool {
+        self.names().iter().any(|namespace| {
+            attr.len() > namespace.len() && attr.starts_with(namespace) && attr.as_bytes()[namespace.len()] == b'.'
+        })
+    }
+}
+
+impl Xattrs {
+    /// Reads the attributes in `namespaces` without following a symlink at `full_path`.
+    pub fn read(full_path: &Path, namespaces: XattrNamespaces) -> io::Result<Self> {
+        let mut attrs = vec![];
+        for name in Self::names_in(full_path, namespaces)? {
+            // An attribute can disappear between listing and reading it
+            if let Some(value) = xattr::get(full_path, &name)? {
+                attrs.push((name, value));
+            }
+        }
+        attrs.sort();
+        Ok(Self { namespaces, attrs })
+    }
+
+    /// Makes the attributes at `full_path` match, removing any in the synced namespaces the
+    /// sender didn't have. Carries on past attributes that can't be set, returning the first
+    /// failure once the rest are done.
+    pub fn apply(&self, full_path: &Path) -> io::Result<()> {
+        let mut failed = None;
+        for name in Self::names_in(full_path, self.namespaces)? {
+            if !self.attrs.iter().any(|&(ref attr, _)| *attr == name) {
+                failed = failed.or(xattr::remove(full_path, &name).err());
+            }
+        }
+
+        for &(ref name, ref value) in &self.attrs {
+            let set = xattr::get(full_path, name).and_then(|current| if current.as_ref() == Some(value) {
+                Ok(())
+            } else {
+                xattr::set(full_path, name, value)
+            });
+            failed = failed.or(set.err());
+        }
+        failed.map_or(Ok(()), Err)
+    }
+
+    fn names_in(full_path: &Path, namespaces: XattrNamespaces) -> io::Result<Vec<String>> {
+        Ok(
+            xattr::list(full_path)?
+                .filter_map(|name: OsString| name.into_string().ok())
+                .filter(|name| namespaces.contains(name))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use test_support::test_dir;
+
+    #[test]
+    fn namespaces_round_trip_through_names() {
+        let namespaces = XattrNamespaces::from_names(vec!["system", "user"]).unwrap();
+        assert_eq!(vec!["user", "system"], namespaces.names());
+        assert!(XattrNamespaces::from_names(vec!["bogus"]).is_err());
+        assert!(XattrNamespaces::default().is_empty());
+    }
+
+    #[test]
+    fn namespaces_match_whole_prefixes() {
+        let namespaces = XattrNamespaces::from_names(vec!["user", "system"]).unwrap();
+        assert!(namespaces.contains("user.tag"));
+        assert!(namespaces.contains("system.posix_acl_access"));
+        assert!(!namespaces.contains("security.selinux"));
+        assert!(!namespaces.contains("username.tag"));
+        assert!(!namespaces.contains("user"));
+    }
+
+    #[test]
+    fn applied_attributes_match_the_source() {
+        let (_dir, dir) = test_dir("bindrs-xattrs");
+        let (src, dst) = (Path::new(&dir).join("src"), Path::new(&dir).join("dst"));
+        fs::write(&src, b"").unwrap();
+        fs::write(&dst, b"").unwrap();
+        // Not every filesystem has a user namespace
+        if xattr::set(&src, "user.tag", b"synced").is_err() {
+            return;
+        }
+        xattr::set(&dst, "user.tag", b"stale").unwrap();
+        xattr::set(&dst, "user.extra", b"unsynced").unwrap();
+
+        Xattrs::read(&src, XattrNamespaces::default()).unwrap().apply(&dst).unwrap();
+        assert_eq!(Some(b"unsynced".to_vec()), xattr::get(&dst, "user.extra").unwrap());
+
+        let user = XattrNamespaces::from_names(vec!["user"]).unwrap();
+        Xattrs::read(&src, user).unwrap().apply(&dst).unwrap();
+        assert_eq!(Some(b"synced".to_vec()), xattr::get(&dst, "user.tag").unwrap());
+        assert_eq!(None, xattr::get(&dst, "user.extra").unwrap());
+        assert!(Xattrs::read(&dst, user).unwrap() == Xattrs::read(&src, user).unwrap());
+    }
+}
@@ -0,0 +1,50 @@
+use error::BindrsError;
+use helpers;
+use slog::{Discard, Logger};
+use std::io::Read;
+use std::thread::sleep;
+use std::time::Duration;
+use structs::bound_file::{BoundFile, FileAction};
+use structs::frame::{Frame, FrameReader, FrameWriter};
+use structs::handshake::Session;
+use structs::pipe::{pipe, PipeReader};
+use tempdir::TempDir;
+
+/// A fresh directory, along with its path resolved the way synced directories are.
+pub fn test_dir(name: &str) -> (TempDir, String) {
+    let dir = TempDir::new(name).unwrap();
+    let path = helpers::resolve_path(dir.path().to_str().unwrap()).unwrap();
+    (dir, path)
+}
+
+/// Sends `path` from `src_dir` and applies it to `dst_dir`, as the two ends of a session would.
+pub fn sync(src_dir: &str, dst_dir: &str, path: &str, action: FileAction, session: &Session) -> Result<(), BindrsError> {
+    let bound_file = BoundFile::build_from_path_action(src_dir, path.to_owned(), action, session)?;
+    receive(dst_dir, send(src_dir, bound_file)?)
+}
+
+pub fn send(src_dir: &str, bound_file: BoundFile) -> Result<FrameReader<PipeReader>, BindrsError> {
+    let (reader, writer) = pipe();
+    let mut writer = FrameWriter::new(writer);
+    bound_file.to_writer(src_dir, &mut writer, None)?;
+    writer.flush()?;
+    Ok(FrameReader::new(reader))
+}
+
+pub fn receive<R: Read>(dst_dir: &str, mut reader: FrameReader<R>) -> Result<(), BindrsError> {
+    match reader.recv()? {
+        (_, Frame::File(bound_file)) => bound_file.save_to_disk(&Logger::root(Discard, o!()), dst_dir, &mut reader),
+        _ => panic!("expected a file header"),
+    }
+}
+
+/// Waits up to ten seconds for `check` to pass, for changes synced in the background.
+pub fn eventually<F: Fn() -> bool>(check: F) -> bool {
+    for _ in 0..200 {
+        if check() {
+            return true;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    false
+}